@@ -6,10 +6,10 @@ use clap::{Args, Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
-static DEFAULT_MESH_X: u16 = 8;
-static DEFAULT_MESH_Y: u16 = 8;
-static DEFAULT_CORES_PER_DSU: u8 = 2;
-static DEFAULT_NODEID_LENGTH: u8 = 9;
+/// Number of cores per DSU assumed for runs which did not record their clusters
+pub static DEFAULT_CORES_PER_DSU: u16 = 2;
+/// Node ID length assumed for runs which did not record it (i.e. the former CLI default)
+pub static DEFAULT_NODEID_LENGTH: u8 = 9;
 
 /* Topology Parameters */
 #[derive(Args, Debug)]
@@ -18,17 +18,51 @@ pub struct DetermineTopologyArgs {
     #[arg(long, value_enum)]
     pub numa_config: NUMAConfig,
 
-    /// Path to benchmark binary
+    /// Path to an external benchmark binary, the benchmark embedded in this binary is run if not set
     #[arg(long)]
-    pub benchmark_binary_path: String,
+    pub benchmark_binary_path: Option<String>,
 
     /// Arguments to benchmark binary (pass as string)
     #[arg(long, trailing_var_arg = true, value_delimiter = ' ', allow_hyphen_values = true)]
     pub benchmark_binary_args: Option<Vec<String>>,
-}
 
+    /// Run the benchmark for this many seconds per core pair (its --duration mode) instead of a fixed number of round trips
+    #[arg(long)]
+    pub benchmark_duration: Option<f64>,
+
+    /// Which DSUs to pair when determining the core placement
+    #[arg(long, value_enum, default_value_t = CoreSweep::Reference)]
+    pub core_sweep: CoreSweep,
+
+    /// DSU pairs to measure with `--core-sweep pairs` (indices of DSUs, e.g. "0:1,2:5"), all pairs if not set
+    #[arg(long, value_delimiter = ',')]
+    pub dsu_pairs: Option<Vec<String>>,
+
+    /// Also record the N/E/S/W link data flits of every XP during the core measurements (needed for route-check)
+    #[arg(long)]
+    pub record_links: bool,
+
+    /// Size of the buffer (in MiB) the benchmark streams over per NUMA node when mapping the memory controllers
+    #[arg(long, default_value_t = 1024, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub memory_size: usize,
+
+    /// Number of cache lines to attribute to HN-Fs to recover the SLC address hashing (skipped if 0)
+    #[arg(long, default_value_t = 0)]
+    pub hash_lines: usize,
+}
 
 #[derive(Clone, Copy, Debug, clap::ValueEnum, Default)]
+pub enum CoreSweep {
+    /// Pair the first DSU with every other DSU
+    #[default]
+    Reference,
+    /// Pair DSUs with each other, either all pairs or those given via --dsu-pairs
+    Pairs
+}
+
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum NUMAConfig {
     #[default]
     Monolithic=0,
@@ -36,13 +70,64 @@ pub enum NUMAConfig {
     Quadrant=2
 }
 
+impl NUMAConfig {
+    /// Number of NUMA nodes per CMN this configuration splits the mesh into
+    pub fn num_nodes(&self) -> usize {
+        match self {
+            NUMAConfig::Monolithic => 1,
+            NUMAConfig::Hemisphere => 2,
+            NUMAConfig::Quadrant   => 4,
+        }
+    }
+}
+
 impl fmt::Display for NUMAConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self)
+        let name = match self {
+            NUMAConfig::Monolithic => "monolithic",
+            NUMAConfig::Hemisphere => "hemisphere",
+            NUMAConfig::Quadrant   => "quadrant",
+        };
+        write!(f, "{name}")
     }
 }
 
+/* Analysis parameters */
+#[derive(Args, Debug)]
+pub struct AnalyzeTopologyArgs {
+    /// Run directory of a previous determine-topology invocation
+    #[arg(long)]
+    pub run_dir: String,
+}
+
+#[derive(Args, Debug)]
+pub struct RouteCheckArgs {
+    /// Run directory of a previous determine-topology invocation with --record-links
+    #[arg(long)]
+    pub run_dir: String,
+
+    /// Links carrying at least this share of the busiest device port's flits count as active
+    #[arg(long, default_value_t = 0.1)]
+    pub threshold: f64,
+}
+
+#[derive(Args, Debug)]
+pub struct DiffTopologyArgs {
+    /// First topology: topology.json, analyze-topology output directory, or determine-topology run directory
+    pub a: String,
+
+    /// Second topology, same formats as the first
+    pub b: String,
+}
+
 /* Launch* parameters */
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum EventPreset {
+    /// Request and data flits of every link and port, and the CXS flits of CMN-700 gateways, reported per CCIX gateway
+    ///  in cmn_N/gateways.csv
+    CrossSocket
+}
+
 #[derive(Args, Debug)]
 pub struct LaunchArgs {
 
@@ -94,37 +179,91 @@ pub(crate) struct LaunchMultiExecutableConfig {
 
 
 /* Main CLI */
+#[derive(Clone, Copy, Debug, clap::ValueEnum, Default)]
+pub enum PerfBackendKind {
+    /// Run `perf stat` and parse its report
+    #[default]
+    Cli,
+    /// Open the events via perf_event_open directly, the perf binary is not needed
+    Native
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum, Default)]
+pub enum EventSchedule {
+    /// Request all events at once, the kernel multiplexes those that do not fit the counters
+    #[default]
+    All,
+    /// Split the events into passes fitting the counters, and run the workload once per pass
+    Passes,
+    /// Split the events into passes fitting the counters, and rotate through them while the workload runs once
+    ///  (needs --perf-backend native)
+    Rotate
+}
+
+
 #[derive(Subcommand,Debug)]
 pub enum Commands {
     DetermineTopology(DetermineTopologyArgs),
+    AnalyzeTopology(AnalyzeTopologyArgs),
+    RouteCheck(RouteCheckArgs),
+    /// Compare two topologies, exits with 1 if they differ
+    DiffTopology(DiffTopologyArgs),
 
     Launch(LaunchArgs),
     LaunchMulti(LaunchMultiArgs),
+
+    /// Run the embedded benchmark, as determine-topology does if no --benchmark-binary-path is given
+    #[command(hide = true)]
+    Benchmark(cmn_benchmark::CliArgs),
 }
 
 #[derive(Parser,Debug)]
 #[command(author,version,about,long_about=None)]
 pub struct Cli {
-    /// Length of Node ID (refer to ARM CMN TRM for mapping)
-    #[arg(long, default_value_t=DEFAULT_NODEID_LENGTH)]
-    pub nodeid_length: u8,
+    /// Length of Node ID (refer to ARM CMN TRM for mapping), detected if not set
+    #[arg(long)]
+    pub nodeid_length: Option<u8>,
 
-    /// Mesh size, X
-    #[arg(long, default_value_t=DEFAULT_MESH_X)]
-    pub mesh_x: u16,
+    /// Mesh size, X (detected if not set)
+    #[arg(long)]
+    pub mesh_x: Option<u16>,
 
-    /// Mesh size, Y
-    #[arg(long, default_value_t=DEFAULT_MESH_Y)]
-    pub mesh_y: u16,
+    /// Mesh size, Y (detected if not set)
+    #[arg(long)]
+    pub mesh_y: Option<u16>,
 
-    /// Number of Cores per DSU
-    #[arg(long, default_value_t=DEFAULT_CORES_PER_DSU)]
-    pub cores_per_dsu: u8,
+    /// Number of Cores per DSU (taken from the CPU clusters in sysfs if not set)
+    #[arg(long)]
+    pub cores_per_dsu: Option<u16>,
 
     /// List of events to record (if launch or launch-multi)
     #[arg(long, value_delimiter = ',')]
     pub events: Option<Vec<String>>,
 
+    /// Predefined set of events to record in addition to --events (if launch or launch-multi)
+    #[arg(long, value_enum)]
+    pub preset: Option<EventPreset>,
+
+    /// Also record the counts of every interval of this many ms as time series (if launch or launch-multi)
+    #[arg(long)]
+    pub interval_ms: Option<u64>,
+
+    /// How to count the CMN PMU events
+    #[arg(long, value_enum, default_value_t = PerfBackendKind::Cli)]
+    pub perf_backend: PerfBackendKind,
+
+    /// Refuse counts of multiplexed events (i.e. not counted the whole time) instead of extrapolating them
+    #[arg(long)]
+    pub strict_multiplexing: bool,
+
+    /// How to count more events than the XPs and DTCs have counters
+    #[arg(long, value_enum, default_value_t = EventSchedule::All)]
+    pub event_schedule: EventSchedule,
+
+    /// Time (ms) to count each pass before rotating to the next one (if --event-schedule rotate)
+    #[arg(long, default_value_t = 100)]
+    pub rotate_interval: u64,
+
     /// Directory to store recorded data in
     #[arg(long, default_value_t=String::from("data"))]
     pub outdir: String,
@@ -0,0 +1,105 @@
+/* Memory bandwidth generator (STREAM-like kernels), with the buffer optionally bound to one NUMA node */
+use core_affinity::CoreId;
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+/// Memory policy of mbind(2) only allowing allocations on the given nodes
+const MPOL_BIND: i32 = 2;
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Kernel {
+    /// Sum up the buffer
+    Read,
+    /// Fill the buffer
+    Write,
+    /// Copy the first half of the buffer into the second half
+    Copy,
+}
+
+/// Run the kernel on the given cores for (at least) `duration` and return the bandwidth in GB/s
+///  The buffer of `size` bytes is split evenly among the cores, each core first touches its chunk,
+///   so the pages end up on its NUMA node unless `numa_node` binds them to another one.
+///  Copy counts bytes read and written, like STREAM does.
+pub fn run(kernel: Kernel, cores: &[CoreId], size: usize, numa_node: Option<u32>, duration: Duration) -> f64 {
+    assert!(!cores.is_empty(), "No cores to run on");
+    assert!(size >= size_of::<u64>(), "Buffer of {size} bytes is too small");
+    let buffer = unsafe {
+        libc::mmap(std::ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+    };
+    assert!(buffer != libc::MAP_FAILED, "Could not allocate buffer of {size} bytes");
+    if let Some(node) = numa_node {
+        assert!(node < 64, "NUMA node {node} is not supported, only nodes 0-63");
+        let nodemask: u64 = 1 << node;
+        // the kernel only takes maxnode - 1 bits of the mask
+        let maxnode = (u64::BITS + 1) as libc::c_ulong;
+        let ret = unsafe {
+            libc::syscall(libc::SYS_mbind, buffer, size, MPOL_BIND, &nodemask as *const u64, maxnode, 0)
+        };
+        assert!(ret == 0, "Could not bind buffer to NUMA node {node}: {}", std::io::Error::last_os_error());
+    }
+
+    let words = unsafe { std::slice::from_raw_parts_mut(buffer as *mut u64, size / size_of::<u64>()) };
+    // rounding up the chunk size can leave cores without a chunk (e.g. 9 words on 4 cores), those are not used
+    let chunks: Vec<_> = words.chunks_mut(words.len().div_ceil(cores.len())).zip(cores).collect();
+    let barrier = Barrier::new(chunks.len());
+    let barrier = &barrier;
+    let results: Vec<(usize, Duration)> = crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = chunks.into_iter().map(|(chunk, core)| s.spawn(move |_| {
+            core_affinity::set_for_current(*core);
+            chunk.fill(0);
+            barrier.wait();
+
+            let start = Instant::now();
+            let mut bytes = 0;
+            let mut pass = 0;
+            while start.elapsed() < duration {
+                bytes += pass_over(kernel, chunk, pass);
+                pass += 1;
+            }
+            (bytes, start.elapsed())
+        })).collect();
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    }).unwrap();
+    unsafe { libc::munmap(buffer, size) };
+
+    let bytes: usize = results.iter().map(|(b, _)| b).sum();
+    let elapsed = results.iter().map(|(_, e)| *e).max().unwrap_or_default();
+    bytes as f64 / elapsed.as_secs_f64() / 1e9
+}
+
+/// One pass of the kernel over the chunk, returning the bytes moved
+fn pass_over(kernel: Kernel, chunk: &mut [u64], pass: u64) -> usize {
+    match kernel {
+        Kernel::Read => {
+            let sum = chunk.iter().fold(0u64, |sum, w| sum.wrapping_add(*w));
+            std::hint::black_box(sum);
+            size_of_val(chunk)
+        }
+        Kernel::Write => {
+            chunk.fill(pass);
+            std::hint::black_box(&chunk);
+            size_of_val(chunk)
+        }
+        Kernel::Copy => {
+            let (src, dst) = chunk.split_at_mut(chunk.len() / 2);
+            dst[..src.len()].copy_from_slice(src);
+            std::hint::black_box(&dst);
+            2 * size_of_val(src)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fewer_chunks_than_cores() {
+        // 9 words on 4 cores are split into 3 chunks of 3 words
+        let cores = [CoreId { id: 0 }; 4];
+        for kernel in [Kernel::Read, Kernel::Write, Kernel::Copy] {
+            assert!(run(kernel, &cores, 9 * size_of::<u64>(), None, Duration::from_millis(1)) > 0.0);
+        }
+    }
+}
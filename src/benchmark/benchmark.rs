@@ -1,11 +1,17 @@
 use core_affinity::CoreId;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Barrier};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-pub struct State {
+use crate::pagemap;
+use crate::timer::Clock;
+
+pub(crate) struct State {
     barrier: Barrier,
     flag: AtomicBool,
+    /// Set by ping once it took its last sample, next to the flag so pong checking it causes no extra traffic
+    done: AtomicBool,
 }
 
 impl State {
@@ -13,16 +19,22 @@ impl State {
         Self {
             barrier: Barrier::new(2),
             flag: AtomicBool::new(PING),
+            done: AtomicBool::new(false),
         }
     }
 }
 
 const PING: bool = false;
 const PONG: bool = true;
+/// Bounce a cache line between both cores via compare-and-swap, returning the duration of every sample of
+///  `num_round_trips` round trips
+///  With a duration, samples are taken until it has passed (at least one) instead of `num_samples` times.
 pub fn run( ping_core: CoreId,
         pong_core: CoreId,
         num_round_trips: u32,
-        num_samples: u32 ) -> u128 {
+        num_samples: u32,
+        duration: Option<Duration>,
+        clock: Clock ) -> Vec<Duration> {
     let state = State::new();
     let r_state = &state;
 
@@ -31,8 +43,10 @@ pub fn run( ping_core: CoreId,
         let pong = s.spawn(move |_| {
             core_affinity::set_for_current(pong_core);
             r_state.barrier.wait();
-            for _ in 0..(num_round_trips*num_samples) {
-                while r_state.flag.compare_exchange(PING, PONG, Ordering::Relaxed, Ordering::Relaxed).is_err() {}
+            loop {
+                while r_state.flag.compare_exchange(PING, PONG, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+                    if r_state.done.load(Ordering::Relaxed) { return }
+                }
             }
         });
 
@@ -40,17 +54,89 @@ pub fn run( ping_core: CoreId,
             core_affinity::set_for_current(ping_core);
             r_state.barrier.wait();
 
-            let start = Instant::now(); // this data will never be used, so use the lower-precision Instant call
-            for _ in 0..num_samples {
+            let begin = Instant::now();
+            let mut samples = Vec::with_capacity(num_samples as usize);
+            while match duration {
+                Some(duration) => samples.is_empty() || begin.elapsed() < duration,
+                None => samples.len() < num_samples as usize
+            } {
+                let start = clock.ticks();
                 for _ in 0..num_round_trips {
                     while r_state.flag.compare_exchange(PONG, PING, Ordering::Relaxed, Ordering::Relaxed).is_err() {}
                 }
+                samples.push(clock.elapsed(start));
             }
-            let end = Instant::now();
-            (end-start).as_micros()
+            r_state.done.store(true, Ordering::Relaxed);
+            samples
         });
 
         pong.join().unwrap();
         ping.join().unwrap()
     }).unwrap()
-}
\ No newline at end of file
+}
+/// Stream over a buffer of `size` bytes on the given core, `num_passes` times, and return the runtime in microseconds
+///  Every page of the buffer is written on that core before timing, so it is allocated on the core's NUMA node (default
+///   policy) and no page faults are timed.
+///  Every pass does a read-modify-write of each cache line, which misses the caches for buffers larger than the SLC
+///   and thus causes requests from the HN-Fs to the memory controllers of that NUMA node.
+pub fn memory_traffic(core: CoreId, size: usize, num_passes: u32) -> u128 {
+    core_affinity::set_for_current(core);
+    // zeroed allocations are mapped lazily, so the first write of every page faults
+    let mut buffer = vec![0u64; size / size_of::<u64>()];
+    buffer.iter_mut().step_by(PAGE_SIZE / size_of::<u64>()).for_each(|v| *v = 1);
+    std::hint::black_box(&mut buffer);
+    let stride = CACHE_LINE_SIZE / size_of::<u64>();
+
+    let start = Instant::now();
+    for pass in 0..num_passes {
+        for i in (0..buffer.len()).step_by(stride) {
+            buffer[i] = buffer[i].wrapping_add(pass as u64);
+        }
+        std::hint::black_box(&mut buffer);
+    }
+    let end = Instant::now();
+    (end-start).as_micros()
+}
+
+const CACHE_LINE_SIZE: usize = 64;
+/// Smallest base page size, touching every PAGE_SIZE bytes touches every page of larger page sizes as well
+const PAGE_SIZE: usize = 4096;
+
+/// Repeatedly evict one cache line from the caches and load it again on the given core, returning its physical address
+///  The line is `line` cache lines into a freshly allocated buffer aligned to 64 KiB (the largest base page size).
+///  Cleaning and invalidating it to the point of coherency makes every load a request to the HN-F its address hashes to.
+pub fn touch_line(core: CoreId, line: usize, num_iterations: u32) -> Option<u64> {
+    core_affinity::set_for_current(core);
+    let layout = Layout::from_size_align((line + 1) * CACHE_LINE_SIZE, 1 << 16).unwrap();
+    let buffer = unsafe { alloc_zeroed(layout) };
+    assert!(!buffer.is_null(), "Could not allocate buffer");
+    let ptr = unsafe { buffer.add(line * CACHE_LINE_SIZE) };
+    unsafe { ptr.write_volatile(1) }; // make sure the page is backed before translating it
+
+    let physical_address = pagemap::physical_address(ptr as usize);
+    for _ in 0..num_iterations {
+        flush_line(ptr);
+        unsafe { ptr.read_volatile() };
+    }
+    unsafe { dealloc(buffer, layout) };
+    physical_address
+}
+
+#[cfg(target_arch = "aarch64")]
+fn flush_line(ptr: *const u8) {
+    unsafe { std::arch::asm!("dc civac, {0}", "dsb ish", in(reg) ptr) }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn flush_line(ptr: *const u8) {
+    unsafe {
+        std::arch::x86_64::_mm_clflush(ptr);
+        std::arch::x86_64::_mm_mfence();
+    }
+}
+
+/// Without cache maintenance instructions every load would hit the cache, so no HN-F would see a request
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+fn flush_line(_ptr: *const u8) {
+    panic!("--hash-line is only supported on aarch64 and x86_64");
+}
@@ -0,0 +1,133 @@
+/* Core-to-core and memory traffic benchmark, used by the benchmark binary and embedded in the measurement binary */
+pub mod bandwidth;
+pub mod benchmark;
+pub mod matrix;
+pub mod pagemap;
+pub mod patterns;
+pub mod stats;
+pub mod timer;
+
+use core_affinity::CoreId;
+use std::time::Duration;
+
+#[derive(Clone)]
+#[derive(clap::Parser, Debug)]
+pub struct CliArgs {
+    /// The number of iterations per sample
+    #[clap(long, default_value_t = 50000, value_parser)]
+    num_iterations: u32,
+
+    /// The number of samples
+    #[clap(long, default_value_t = 5000, value_parser)]
+    num_samples: u32,
+
+    /// Specify the cores by id that should be used, comma delimited. By default all cores are used.
+    #[clap(short, long, value_delimiter=',', value_parser)]
+    cores: Vec<usize>,
+
+    /// Instead of bouncing a cache line between two cores, stream over a buffer of this many MiB on the first core
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    memory_size: Option<usize>,
+
+    /// Number of passes over the buffer with --memory-size
+    #[clap(long, default_value_t = 20, value_parser)]
+    memory_passes: u32,
+
+    /// Instead, repeatedly flush and load the cache line with this index on the first core (--num-iterations times)
+    ///  and print its physical address
+    #[clap(long, value_parser)]
+    hash_line: Option<usize>,
+
+    /// Measure every pair of --cores (all cores if not given) or the pairs given via --pairs, printing one latency per pair
+    #[clap(long)]
+    matrix: bool,
+
+    /// Core pairs to measure with --matrix, e.g. "0:1,0:8"
+    #[clap(long, value_delimiter=',', value_parser)]
+    pairs: Vec<String>,
+
+    /// Instead, run this bandwidth kernel on all given cores (all cores if not given) and print the bandwidth in GB/s
+    #[clap(long, value_enum)]
+    bandwidth: Option<bandwidth::Kernel>,
+
+    /// NUMA node to bind the --bandwidth buffer (of --memory-size MiB, 1024 if not given) to
+    #[clap(long, value_parser)]
+    numa_node: Option<u32>,
+
+    /// Run each pattern (pair) for this many seconds, taking samples of --num-iterations round trips until then
+    ///  instead of --num-samples, and print the number of round trips completed. Duration of --bandwidth (default 10)
+    #[clap(long, value_parser)]
+    duration: Option<f64>,
+
+    /// Cache line sharing pattern to run on the given cores
+    #[clap(long, value_enum, default_value_t = patterns::Pattern::Cas)]
+    pattern: patterns::Pattern,
+
+    /// Number of lines of the stride pattern
+    #[clap(long, default_value_t = 4, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    lines: usize,
+
+    /// Distance of the lines of the stride pattern, in cache lines
+    #[clap(long, default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    stride: usize,
+
+    /// Print latency statistics per round trip (min, median, mean, p99, stddev over all samples) in this format,
+    ///  instead of the total runtime in microseconds. --matrix always prints statistics, as CSV if not given
+    #[clap(long, value_enum)]
+    format: Option<matrix::OutputFormat>,
+
+    /// Timer to take the samples with, cntvct reads the ARM generic timer counter directly (aarch64 only),
+    ///  cycles counts CPU cycles with perf
+    #[clap(long, value_enum, default_value_t = timer::Timer::Instant)]
+    timer: timer::Timer,
+}
+
+/// Run the benchmark mode selected by the arguments, printing its result on stdout
+pub fn run(args: CliArgs) {
+    if let Some(line) = args.hash_line {
+        match benchmark::touch_line(CoreId { id: args.cores[0] }, line, args.num_iterations) {
+            Some(physical_address) => println!("{physical_address:#x}"),
+            None => eprintln!("Could not translate address via /proc/self/pagemap, run as root")
+        }
+        return
+    }
+    if let Some(kernel) = args.bandwidth {
+        let cores = match args.cores.is_empty() {
+            true => core_affinity::get_core_ids().expect("Could not get core IDs"),
+            false => args.cores.iter().map(|id| CoreId { id: *id }).collect()
+        };
+        let r = bandwidth::run(kernel, &cores, args.memory_size.unwrap_or(1024) << 20, args.numa_node,
+                               Duration::from_secs_f64(args.duration.unwrap_or(10.0)));
+        println!("{r:.2}");
+        return
+    }
+    let sampling = patterns::Sampling {
+        num_round_trips: args.num_iterations,
+        num_samples: args.num_samples,
+        duration: args.duration.map(Duration::from_secs_f64),
+        clock: timer::Clock::new(args.timer),
+    };
+    if args.matrix {
+        let pairs = matrix::get_pairs(&args.cores, &args.pairs);
+        let latencies = matrix::run(&pairs, args.pattern, sampling, args.lines, args.stride);
+        println!("{}", matrix::format(&latencies, args.format.unwrap_or(matrix::OutputFormat::Csv)));
+        return
+    }
+    if let Some(memory_size) = args.memory_size {
+        let r = benchmark::memory_traffic(CoreId { id: args.cores[0] }, memory_size << 20, args.memory_passes);
+        println!("{r}");
+        return
+    }
+    let cores: Vec<CoreId> = args.cores.iter().map(|id| CoreId { id: *id }).collect();
+    let samples = patterns::run(args.pattern, &cores, sampling, args.lines, args.stride);
+    match (args.format, sampling.duration) {
+        (None, None) => println!("{}", samples.iter().sum::<Duration>().as_micros()),
+        (None, Some(_)) => println!("{}", samples.len() as u64 * args.num_iterations as u64),
+        (Some(matrix::OutputFormat::Csv), _) => {
+            println!("{}", stats::Stats::csv_header());
+            println!("{}", stats::Stats::new(&samples, args.num_iterations).to_csv());
+        }
+        (Some(matrix::OutputFormat::Json), _) => println!("{}", serde_json::to_string_pretty(
+            &stats::Stats::new(&samples, args.num_iterations)).expect("Could not serialize statistics")),
+    }
+}
\ No newline at end of file
@@ -0,0 +1,62 @@
+/* All-pairs mode: run the ping-pong benchmark on many core pairs within one process */
+use core_affinity::CoreId;
+use serde::Serialize;
+
+use crate::patterns::{self, Pattern, Sampling};
+use crate::stats::Stats;
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    Json
+}
+
+#[derive(Serialize, Debug)]
+pub struct PairLatency {
+    pub core_a: usize,
+    pub core_b: usize,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+/// Core pairs to measure: the given `a:b` pairs, or every pair of the given cores (all cores if none given)
+pub fn get_pairs(cores: &[usize], pairs: &[String]) -> Vec<(usize, usize)> {
+    if !pairs.is_empty() {
+        return pairs.iter().map(|pair| {
+            pair.split_once(':')
+                .and_then(|(a, b)| Some((a.parse().ok()?, b.parse().ok()?)))
+                .unwrap_or_else(|| panic!("Invalid core pair `{pair}`, expected `a:b`"))
+        }).collect()
+    }
+    let cores = match cores.is_empty() {
+        true => core_affinity::get_core_ids().expect("Could not get core IDs").into_iter().map(|c| c.id).collect(),
+        false => cores.to_vec()
+    };
+    cores.iter().enumerate()
+        .flat_map(|(i, a)| cores.iter().skip(i + 1).map(move |b| (*a, *b)))
+        .collect()
+}
+
+/// Run the ping-pong pattern on every pair one after another, progress is reported on stderr
+pub fn run(pairs: &[(usize, usize)], pattern: Pattern, sampling: Sampling, num_lines: usize, stride: usize)
+           -> Vec<PairLatency> {
+    let latencies = pairs.iter().enumerate().map(|(n, (a, b))| {
+        eprint!("\r[{}/{}]", n + 1, pairs.len());
+        let samples = patterns::run(pattern, &[CoreId { id: *a }, CoreId { id: *b }], sampling, num_lines, stride);
+        PairLatency { core_a: *a, core_b: *b, stats: Stats::new(&samples, sampling.num_round_trips) }
+    }).collect();
+    eprintln!(); // newline to end \r shenanigans
+    latencies
+}
+
+/// One `core_a;core_b;samples;min_ns;...` line per pair, or a JSON list of pairs
+pub fn format(latencies: &[PairLatency], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Csv => {
+            let mut lines = vec![format!("core_a;core_b;{}", Stats::csv_header())];
+            lines.extend(latencies.iter().map(|l| format!("{};{};{}", l.core_a, l.core_b, l.stats.to_csv())));
+            lines.join("\n")
+        }
+        OutputFormat::Json => serde_json::to_string_pretty(latencies).expect("Could not serialize latencies")
+    }
+}
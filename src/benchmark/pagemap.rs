@@ -0,0 +1,20 @@
+/* Translation of virtual to physical addresses via /proc/self/pagemap (see Documentation/admin-guide/mm/pagemap.rst) */
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+/// Physical address of the given virtual address of this process
+///  None if the page is not present, or if the PFN is hidden (reading PFNs requires CAP_SYS_ADMIN)
+pub fn physical_address(virtual_address: usize) -> Option<u64> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let mut entry = [0u8; 8];
+    File::open("/proc/self/pagemap").ok()?
+        .read_exact_at(&mut entry, (virtual_address / page_size * 8) as u64).ok()?;
+    let entry = u64::from_le_bytes(entry);
+
+    // bit 63: page present, bits 0-54: page frame number
+    let pfn = entry & ((1 << 55) - 1);
+    if entry >> 63 == 0 || pfn == 0 {
+        return None
+    }
+    Some(pfn * page_size as u64 + (virtual_address % page_size) as u64)
+}
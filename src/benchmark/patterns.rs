@@ -0,0 +1,181 @@
+/* Cache line sharing patterns besides the CAS ping-pong of benchmark::run, each causing different CHI flows on the mesh */
+use core_affinity::CoreId;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+use crate::benchmark;
+use crate::timer::Clock;
+
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Pattern {
+    /// Compare-and-swap ping-pong on one line between two cores (snoops and data transfers)
+    #[default]
+    Cas,
+    /// Ping-pong with plain loads and stores on one line between two cores
+    LoadStore,
+    /// The first core writes a line, all other cores read it and acknowledge on their own line (snoop broadcasts)
+    ReadShared,
+    /// All cores store to the same line concurrently (ownership bouncing without atomics)
+    WriteWrite,
+    /// All cores atomically add to the same line, using LSE `ldadd` if available (far atomics at the HN-F)
+    AtomicAdd,
+    /// All cores atomically swap the same line, using LSE `swp` if available (far atomics at the HN-F)
+    AtomicSwap,
+    /// Load/store ping-pong on several lines at once, `--lines` lines spaced `--stride` lines apart
+    Stride,
+}
+
+/// How to take the samples of a pattern
+#[derive(Clone, Copy, Debug)]
+pub struct Sampling {
+    /// Round trips (or iterations) per sample
+    pub num_round_trips: u32,
+    pub num_samples: u32,
+    /// Take samples until this has passed, instead of num_samples
+    pub duration: Option<Duration>,
+    pub clock: Clock,
+}
+
+/// One cache line
+#[repr(align(64))]
+#[derive(Default)]
+struct Line(AtomicU64);
+
+/// Run the given pattern on the given cores and return the duration of every sample of `num_round_trips` round trips,
+///  as seen by the first core
+///  Ping-pong patterns (cas, load-store, stride) use the first two cores, all others every given core.
+pub fn run(pattern: Pattern, cores: &[CoreId], sampling: Sampling, num_lines: usize, stride: usize) -> Vec<Duration> {
+    let sample = |cores: &[CoreId], step: &(dyn Fn(usize, u64) + Sync)| scoped(cores, sampling, step);
+    match pattern {
+        Pattern::Cas => benchmark::run(cores[0], cores[1], sampling.num_round_trips, sampling.num_samples,
+                                       sampling.duration, sampling.clock),
+        Pattern::LoadStore => {
+            let line = Line::default();
+            sample(&cores[..2], &|i, n| ping_pong(&[&line], i, n))
+        }
+        Pattern::Stride => {
+            let lines: Vec<Line> = (0..num_lines * stride).map(|_| Line::default()).collect();
+            let lines: Vec<&Line> = lines.iter().step_by(stride).collect();
+            sample(&cores[..2], &|i, n| ping_pong(&lines, i, n))
+        }
+        Pattern::ReadShared => {
+            let value = Line::default();
+            let acks: Vec<Line> = cores.iter().map(|_| Line::default()).collect();
+            sample(cores, &|i, n| read_shared(&value, &acks, i, n + 1))
+        }
+        Pattern::WriteWrite => {
+            let line = Line::default();
+            sample(cores, &|_, n| line.0.store(n, Ordering::Relaxed))
+        }
+        Pattern::AtomicAdd => {
+            let line = Line::default();
+            sample(cores, &|_, _| { atomic_add(&line.0); })
+        }
+        Pattern::AtomicSwap => {
+            let line = Line::default();
+            sample(cores, &|_, n| { atomic_swap(&line.0, n); })
+        }
+    }
+}
+
+/// Run `step(thread index, iteration)` on every core at once, after all threads have started, returning the duration
+///  of every sample of `num_round_trips` iterations of the first core
+///  The first core decides whether to take another sample (`num_samples` in total, or until `duration` has passed but at
+///   least one) and publishes the number of samples to take, so all cores stop after the same sample.
+fn scoped(cores: &[CoreId], sampling: Sampling, step: &(dyn Fn(usize, u64) + Sync)) -> Vec<Duration> {
+    let Sampling { num_round_trips, num_samples, duration, clock } = sampling;
+    let barrier = Barrier::new(cores.len());
+    let (go, done) = (AtomicU64::new(0), AtomicBool::new(false));
+    let (barrier, go, done) = (&barrier, &go, &done);
+    crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = cores.iter().enumerate().map(|(i, core)| s.spawn(move |_| {
+            core_affinity::set_for_current(*core);
+            barrier.wait();
+            let begin = Instant::now();
+            let mut samples = Vec::new();
+            for sample in 0.. {
+                if i == 0 {
+                    let more = match duration {
+                        Some(duration) => sample == 0 || begin.elapsed() < duration,
+                        None => sample < num_samples as u64
+                    };
+                    match more {
+                        true => go.store(sample + 1, Ordering::Release),
+                        false => done.store(true, Ordering::Release)
+                    }
+                }
+                // load done first, so a final go is seen
+                loop {
+                    let stop = done.load(Ordering::Acquire);
+                    if go.load(Ordering::Acquire) > sample { break }
+                    if stop { return samples }
+                }
+                let start = clock.ticks();
+                for round_trip in 0..num_round_trips as u64 {
+                    step(i, sample * num_round_trips as u64 + round_trip);
+                }
+                samples.push(clock.elapsed(start));
+            }
+            samples
+        })).collect();
+        threads.into_iter().map(|t| t.join().unwrap()).next().unwrap()
+    }).unwrap()
+}
+
+/// One ping-pong via plain loads and stores: ping writes odd, pong writes even sequence numbers into every line
+fn ping_pong(lines: &[&Line], i: usize, n: u64) {
+    let (wait_for, write) = (2 * n + i as u64, 2 * n + i as u64 + 1);
+    while lines[lines.len() - 1].0.load(Ordering::Acquire) != wait_for {}
+    for line in lines {
+        line.0.store(write, Ordering::Release);
+    }
+}
+
+/// The first core publishes sequence number `n`, all others read it and acknowledge it on their own line
+fn read_shared(value: &Line, acks: &[Line], i: usize, n: u64) {
+    match i {
+        0 => {
+            value.0.store(n, Ordering::Release);
+            while acks[1..].iter().any(|a| a.0.load(Ordering::Acquire) != n) {}
+        }
+        _ => {
+            while value.0.load(Ordering::Acquire) != n {}
+            acks[i].0.store(n, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn atomic_add(line: &AtomicU64) -> u64 {
+    if std::arch::is_aarch64_feature_detected!("lse") {
+        let old: u64;
+        unsafe { std::arch::asm!(".arch_extension lse", "ldadd {1}, {0}, [{2}]",
+                                 out(reg) old, in(reg) 1u64, in(reg) line.as_ptr()) };
+        old
+    } else {
+        line.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn atomic_swap(line: &AtomicU64, value: u64) -> u64 {
+    if std::arch::is_aarch64_feature_detected!("lse") {
+        let old: u64;
+        unsafe { std::arch::asm!(".arch_extension lse", "swp {1}, {0}, [{2}]",
+                                 out(reg) old, in(reg) value, in(reg) line.as_ptr()) };
+        old
+    } else {
+        line.swap(value, Ordering::Relaxed)
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn atomic_add(line: &AtomicU64) -> u64 {
+    line.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn atomic_swap(line: &AtomicU64, value: u64) -> u64 {
+    line.swap(value, Ordering::Relaxed)
+}
@@ -0,0 +1,94 @@
+/* Statistics over the per-sample timings of the round trip patterns */
+use std::time::Duration;
+use serde::Serialize;
+
+/// Statistics of the latency per round trip (or iteration) over all samples, in nanoseconds
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    pub samples: usize,
+    /// Round trips over all samples
+    pub round_trips: u64,
+    pub min_ns: f64,
+    pub median_ns: f64,
+    pub mean_ns: f64,
+    pub p99_ns: f64,
+    pub stddev_ns: f64,
+}
+
+impl Stats {
+    /// Statistics of samples, each the duration of `num_round_trips` round trips
+    pub fn new(samples: &[Duration], num_round_trips: u32) -> Stats {
+        assert!(!samples.is_empty(), "No samples taken");
+        let mut latencies: Vec<f64> = samples.iter()
+            .map(|s| s.as_nanos() as f64 / num_round_trips as f64)
+            .collect();
+        latencies.sort_by(|a, b| a.total_cmp(b));
+
+        let n = latencies.len();
+        let mean = latencies.iter().sum::<f64>() / n as f64;
+        Stats {
+            samples: n,
+            round_trips: n as u64 * num_round_trips as u64,
+            min_ns: latencies[0],
+            median_ns: match n % 2 {
+                0 => (latencies[n / 2 - 1] + latencies[n / 2]) / 2.0,
+                _ => latencies[n / 2]
+            },
+            mean_ns: mean,
+            // nearest rank
+            p99_ns: latencies[((n as f64 * 0.99).ceil() as usize).max(1) - 1],
+            stddev_ns: (latencies.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / n as f64).sqrt(),
+        }
+    }
+
+    pub fn csv_header() -> &'static str {
+        "samples;round_trips;min_ns;median_ns;mean_ns;p99_ns;stddev_ns"
+    }
+
+    pub fn to_csv(&self) -> String {
+        format!("{};{};{:.2};{:.2};{:.2};{:.2};{:.2}",
+                self.samples, self.round_trips, self.min_ns, self.median_ns, self.mean_ns, self.p99_ns, self.stddev_ns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(ns: &[u64]) -> Vec<Duration> {
+        ns.iter().map(|n| Duration::from_nanos(*n)).collect()
+    }
+
+    #[test]
+    fn single_sample() {
+        let s = Stats::new(&samples(&[500]), 10);
+        assert_eq!((s.samples, s.round_trips), (1, 10));
+        assert_eq!((s.min_ns, s.median_ns, s.mean_ns, s.p99_ns, s.stddev_ns), (50.0, 50.0, 50.0, 50.0, 0.0));
+    }
+
+    #[test]
+    fn odd_samples() {
+        // unsorted on purpose
+        let s = Stats::new(&samples(&[30, 10, 20]), 1);
+        assert_eq!((s.min_ns, s.median_ns, s.mean_ns, s.p99_ns), (10.0, 20.0, 20.0, 30.0));
+        assert!((s.stddev_ns - (200.0_f64 / 3.0).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn even_samples() {
+        let s = Stats::new(&samples(&[40, 10, 30, 20]), 2);
+        assert_eq!((s.samples, s.round_trips), (4, 8));
+        assert_eq!((s.min_ns, s.median_ns, s.mean_ns, s.p99_ns), (5.0, 12.5, 12.5, 20.0));
+        assert!((s.stddev_ns - 1.25_f64.sqrt() * 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn p99_nearest_rank() {
+        // rank ceil(0.99 * 100) = 99, so the largest of 100 samples is not the p99
+        let s = Stats::new(&samples(&(1..=100).collect::<Vec<u64>>()), 1);
+        assert_eq!((s.median_ns, s.p99_ns), (50.5, 99.0));
+        // rank ceil(0.99 * 101) = 100
+        let s = Stats::new(&samples(&(1..=101).collect::<Vec<u64>>()), 1);
+        assert_eq!((s.median_ns, s.p99_ns), (51.0, 100.0));
+    }
+}
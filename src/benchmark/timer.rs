@@ -0,0 +1,149 @@
+/* Timers to take the samples with: std::time::Instant, the ARM generic timer counter read directly, or CPU cycles */
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::FromRawFd;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use perf_event_open_sys as sys;
+
+/// Duration of the calibration of the counter frequency against Instant
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+/// Maximum deviation of the calibrated from the reported (CNTFRQ_EL0) frequency, before the calibrated one is used
+const CALIBRATION_TOLERANCE: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Timer {
+    /// std::time::Instant (clock_gettime)
+    #[default]
+    Instant,
+    /// Virtual counter of the ARM generic timer (CNTVCT_EL0), read without a syscall or vDSO call.
+    ///  Instant reads the same counter via the vDSO on aarch64 Linux, so this only saves the call overhead,
+    ///  the resolution is one tick of the counter frequency, typically 25-100 MHz.
+    ///  Falls back to Instant on other architectures
+    Cntvct,
+    /// User space CPU cycles of the sampling thread, counted by a perf cycles counter (read via read(2)).
+    ///  Converted to time with the frequency calibrated at startup, so only exact at a fixed CPU frequency.
+    ///  Falls back to cntvct if no cycles counter can be opened (kernel.perf_event_paranoid > 2, or in VMs)
+    Cycles,
+}
+
+/// Calibrated timer, cheap to copy into the benchmark threads
+#[derive(Clone, Copy, Debug)]
+pub struct Clock {
+    timer: Timer,
+    /// Ticks per second
+    frequency: f64,
+}
+
+impl Clock {
+    /// Clock reading the given timer, calibrating the counter frequency if needed
+    pub fn new(timer: Timer) -> Clock {
+        match timer {
+            Timer::Instant => Clock { timer, frequency: 1e9 },
+            Timer::Cntvct if !cfg!(target_arch = "aarch64") => {
+                eprintln!("CNTVCT_EL0 is only available on aarch64, falling back to Instant");
+                Clock { timer: Timer::Instant, frequency: 1e9 }
+            }
+            Timer::Cntvct => Clock { timer, frequency: calibrate("CNTVCT_EL0", cntvct, Some(cntfrq() as f64)) },
+            Timer::Cycles if cycles().is_none() => {
+                eprintln!("Could not open a perf cycles counter, falling back to cntvct");
+                Clock::new(Timer::Cntvct)
+            }
+            Timer::Cycles => Clock { timer, frequency: calibrate("Cycles counter", || cycles().unwrap(), None) }
+        }
+    }
+
+    /// Current counter value in ticks
+    #[inline(always)]
+    pub fn ticks(&self) -> u64 {
+        match self.timer {
+            Timer::Instant => epoch().elapsed().as_nanos() as u64,
+            Timer::Cntvct => cntvct(),
+            Timer::Cycles => cycles().expect("Could not read the perf cycles counter")
+        }
+    }
+
+    /// Time passed since `start` ticks
+    #[inline(always)]
+    pub fn elapsed(&self, start: u64) -> Duration {
+        Duration::from_secs_f64(self.ticks().wrapping_sub(start) as f64 / self.frequency)
+    }
+}
+
+/// Common start of all Instant based clocks
+fn epoch() -> &'static Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now)
+}
+
+/// Frequency of the counter as measured against Instant, preferring the reported one (CNTFRQ_EL0) if it matches
+///  CNTFRQ_EL0 is set by firmware and thus not always correct. The frequency is printed, as it is the timer's resolution.
+fn calibrate(name: &str, ticks: impl Fn() -> u64, reported: Option<f64>) -> f64 {
+    let (start, start_ticks) = (Instant::now(), ticks());
+    while start.elapsed() < CALIBRATION_TIME {}
+    let calibrated = ticks().wrapping_sub(start_ticks) as f64 / start.elapsed().as_secs_f64();
+    let frequency = match reported {
+        Some(reported) if (calibrated - reported).abs() <= reported * CALIBRATION_TOLERANCE => reported,
+        Some(reported) => {
+            eprintln!("{name} reports {reported} Hz, but runs at {calibrated:.0} Hz, using the latter");
+            calibrated
+        }
+        None => calibrated
+    };
+    eprintln!("{name} runs at {:.2} MHz, {:.2} ns per tick", frequency / 1e6, 1e9 / frequency);
+    frequency
+}
+
+/// User space CPU cycles of the calling thread, None if no perf cycles counter could be opened
+///  Every thread opens its own counter on first use.
+#[inline(always)]
+fn cycles() -> Option<u64> {
+    thread_local! {
+        static COUNTER: Option<File> = open_cycles_counter();
+    }
+    COUNTER.with(|counter| {
+        let mut value = [0u8; 8];
+        counter.as_ref()?.read_exact(&mut value).ok()?;
+        Some(u64::from_ne_bytes(value))
+    })
+}
+
+/// Enabled perf cycles counter of the calling thread, excluding the kernel so it is permitted up to perf_event_paranoid 2
+fn open_cycles_counter() -> Option<File> {
+    let mut attr = sys::bindings::perf_event_attr {
+        type_: sys::bindings::perf_type_id_PERF_TYPE_HARDWARE,
+        size: size_of::<sys::bindings::perf_event_attr>() as u32,
+        config: sys::bindings::perf_hw_id_PERF_COUNT_HW_CPU_CYCLES as u64,
+        ..Default::default()
+    };
+    attr.set_exclude_kernel(1);
+    attr.set_exclude_hv(1);
+    let fd = unsafe { sys::perf_event_open(&mut attr, 0, -1, -1, sys::bindings::PERF_FLAG_FD_CLOEXEC as u64) };
+    (fd >= 0).then(|| unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+fn cntvct() -> u64 {
+    let ticks: u64;
+    // isb keeps the read from being executed ahead of the code it is supposed to time
+    unsafe { std::arch::asm!("isb", "mrs {}, cntvct_el0", out(reg) ticks, options(nostack)) };
+    ticks
+}
+
+#[cfg(target_arch = "aarch64")]
+fn cntfrq() -> u64 {
+    let frequency: u64;
+    unsafe { std::arch::asm!("mrs {}, cntfrq_el0", out(reg) frequency, options(nomem, nostack)) };
+    frequency
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn cntvct() -> u64 {
+    unreachable!("CNTVCT_EL0 is only available on aarch64")
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn cntfrq() -> u64 {
+    unreachable!("CNTFRQ_EL0 is only available on aarch64")
+}
@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::{debug, info, warn};
+
+use crate::args::{AnalyzeTopologyArgs, DEFAULT_CORES_PER_DSU, DEFAULT_NODEID_LENGTH};
+use crate::commands::determine_memory::{HNF_MC_EVENT, PORT_REQ_EVENTS};
+use crate::commands::determine_nodes::GATEWAY_EVENT_PREFIXES;
+use crate::commands::validate::validate;
+use crate::debugfs;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::topology::{MemoryMap, MeshInfo, NodeType, NumaLayout, NumaMemory, Port, Topology, Xp};
+use crate::utils::{cmn_folder, get_event, read_events_map, read_json};
+use crate::writer::Writer;
+
+pub type PortLocation = (u16, u16, u16);
+
+/// Share of the busiest HN-F / port a node has to reach to count as serving a NUMA node
+static MEMORY_ACTIVITY_THRESHOLD: f64 = 0.1;
+
+/// Analyze the output of determine-topology and write the derived topology of every CMN as `cmn_N/topology.json`
+///  The topology is validated against rules that always hold, violations are written to `cmn_N/findings.csv`
+///  If the debugfs map of the arm-cmn driver has been captured, it is cross-checked against the derived topology
+///  If the memory controllers have been measured, the HN-Fs and SN-Fs per NUMA node are written to `cmn_N/memory_map.json`
+pub fn analyze(args: &AnalyzeTopologyArgs, nodeid_length: Option<u8>, cores_per_dsu: Option<u16>, writer: &Writer)
+               -> Result<()> {
+    let run_dir = Path::new(&args.run_dir);
+    for (cmn_idx, cmn_dir) in cmn_run_dirs(run_dir)? {
+        info!("Analyzing Topology of CMN {cmn_idx} in {:?}", cmn_dir);
+        let topology = build_topology(run_dir, cmn_idx, nodeid_length, cores_per_dsu)?;
+        writer.write_json(&topology, "topology.json", Some(cmn_folder(cmn_idx).as_str()))?;
+        writer.write_lines(validate(run_dir, cmn_dir.as_path(), &topology)?, "findings.csv",
+                           Some(cmn_folder(cmn_idx).as_str()))?;
+
+        if let Ok(content) = fs::read_to_string(cmn_dir.join("debugfs_map.txt")) {
+            let disagreements = debugfs::crosscheck(&topology, &debugfs::parse(content.as_str()));
+            info!("{} disagreements between counters and debugfs map", disagreements.len() - 1);
+            writer.write_lines(disagreements, "debugfs_crosscheck.csv", Some(cmn_folder(cmn_idx).as_str()))?;
+        }
+
+        if cmn_dir.join("memory").is_dir() {
+            let memory_map = map_memory(run_dir, cmn_dir.as_path(), &topology)?;
+            writer.write_json(&memory_map, "memory_map.json", Some(cmn_folder(cmn_idx).as_str()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Per-CMN directories (`cmn_N`) of a determine-topology run
+///  Runs recorded before multi-CMN support keep everything in the run directory itself, which is then treated as CMN 0
+pub fn cmn_run_dirs(run_dir: &Path) -> Result<Vec<(u8, PathBuf)>> {
+    let mut dirs: Vec<(u8, PathBuf)> = fs::read_dir(run_dir).map_err(Error::io(run_dir))?
+        .filter_map(|f| f.ok())
+        .filter(|f| f.path().is_dir())
+        .filter_map(|f| Some((f.file_name().to_str()?.strip_prefix("cmn_")?.parse().ok()?, f.path())))
+        .collect();
+    if dirs.is_empty() {
+        dirs.push((0, run_dir.to_path_buf()));
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Node ID length of a CMN directory, as recorded by determine-mesh in `mesh.json`
+fn recorded_nodeid_length(cmn_dir: &Path) -> Result<u8> {
+    Ok(match read_json::<MeshInfo>(cmn_dir.join("mesh.json").as_path())? {
+        Some(mesh) => mesh.nodeid_length,
+        None => {
+            warn!("No mesh.json in {:?}, assuming node ID length {DEFAULT_NODEID_LENGTH}", cmn_dir);
+            DEFAULT_NODEID_LENGTH
+        }
+    })
+}
+
+/// CPU clusters of a CMN directory, as recorded by determine-cores in `clusters.json`
+pub fn recorded_clusters(cmn_dir: &Path) -> Result<Vec<Vec<u16>>> {
+    Ok(match read_json(cmn_dir.join("clusters.json").as_path())? {
+        Some(clusters) => clusters,
+        None => {
+            warn!("No clusters.json in {:?}, assuming {DEFAULT_CORES_PER_DSU} cores per DSU", cmn_dir);
+            Vec::new()
+        }
+    })
+}
+
+/// Build the topology model of one CMN from a determine-topology run directory
+///  mxp.csv gives the existing XPs, nodes.csv the HN-F/HN-I/RN-D/CXG ports, and cores/ the RN-F ports incl. their CPUs
+///  Without an explicit nodeid_length or cores_per_dsu, the node ID length and CPU clusters recorded during the run are used
+///  A port classified as several node types keeps the last one in NodeType order, the others are recorded as conflicting
+pub fn build_topology(run_dir: &Path, cmn_idx: u8, nodeid_length: Option<u8>, cores_per_dsu: Option<u16>)
+                      -> Result<Topology> {
+    let events_map = read_events_map(run_dir.join("events.csv").as_path())?;
+    let run_dir = cmn_run_dirs(run_dir)?.into_iter()
+        .find(|(idx, _)| *idx == cmn_idx)
+        .map(|(_, dir)| dir)
+        .ok_or_else(|| Error::Config(format!("No data for CMN {cmn_idx} in {}", run_dir.display())))?;
+    let run_dir = run_dir.as_path();
+    let nodeid_length = match nodeid_length {
+        Some(nodeid_length) => nodeid_length,
+        None => recorded_nodeid_length(run_dir)?
+    };
+    let event = |name: &str| get_event(&events_map, name);
+
+    let mxp_events = Event::from_csv(run_dir.join("mxp.csv").as_path(), nodeid_length)?;
+    let mut xps: Vec<(u16, u16)> = mxp_events.iter()
+        .filter(|e| e.counts >= 0)
+        .map(|e| (e.node_id.x, e.node_id.y))
+        .collect();
+    xps.sort();
+    xps.dedup();
+    debug!("Found {} XPs", xps.len());
+
+    let mut node_types: BTreeMap<PortLocation, Vec<NodeType>> = BTreeMap::new();
+    let node_events = Event::from_csv(run_dir.join("nodes.csv").as_path(), nodeid_length)?;
+    for (name, node_type) in [("hnf_seq_full", NodeType::HnF),
+                              ("hni_arready_no_arvalid", NodeType::HnI),
+                              ("rnid_rdb_hybrid", NodeType::RnD)] {
+        let (event_type, event_id) = event(name)?;
+        node_events.iter()
+            .filter(|e| e.event_type == event_type && e.event_id == event_id && e.counts >= 0)
+            .for_each(|e| node_types.entry((e.node_id.x, e.node_id.y, e.node_id.port))
+                .or_default()
+                .push(node_type));
+    }
+    // gateway events are only exposed on multi-socket systems, all events of a node share its type
+    for event_type in GATEWAY_EVENT_PREFIXES.iter()
+            .filter_map(|prefix| events_map.iter().find(|(name, _)| name.starts_with(prefix)))
+            .map(|(_, (event_type, _))| *event_type) {
+        node_events.iter()
+            .filter(|e| e.event_type == event_type && e.counts >= 0)
+            .for_each(|e| node_types.entry((e.node_id.x, e.node_id.y, e.node_id.port))
+                .or_default()
+                .push(NodeType::Cxg));
+    }
+
+    let port_events = [event("mxp_p0_dat_txflit_valid")?, event("mxp_p1_dat_txflit_valid")?];
+    let clusters = match cores_per_dsu {
+        Some(_) => Vec::new(),
+        None => recorded_clusters(run_dir)?
+    };
+    let cpus = place_cpus(run_dir, nodeid_length, &clusters, cores_per_dsu.unwrap_or(DEFAULT_CORES_PER_DSU),
+                          &port_events)?;
+    for location in cpus.keys() {
+        node_types.entry(*location).or_default().push(NodeType::RnF);
+    }
+
+    let xps = xps.into_iter().map(|(x, y)| Xp {
+        x, y,
+        ports: (0..=1).map(|port| {
+            let mut types = node_types.get(&(x, y, port)).cloned().unwrap_or_default();
+            types.sort();
+            types.dedup();
+            Port {
+                port,
+                node_type: types.pop().unwrap_or(NodeType::XpOnly),
+                cpus: cpus.get(&(x, y, port)).cloned().unwrap_or_default(),
+                conflicting_types: types
+            }
+        }).collect()
+    }).collect::<Vec<Xp>>();
+
+    Ok(Topology {
+        cmn_idx,
+        nodeid_length,
+        mesh_x: xps.iter().map(|xp| xp.x + 1).max().unwrap_or(0),
+        mesh_y: xps.iter().map(|xp| xp.y + 1).max().unwrap_or(0),
+        xps
+    })
+}
+
+/// Read all `cores/cores_R_N.csv` files of one CMN directory, keyed by the core pair (R, N)
+pub fn read_core_measurements(run_dir: &Path, nodeid_length: u8) -> Result<BTreeMap<(u16, u16), Vec<Event>>> {
+    let mut measurements = BTreeMap::new();
+    let cores_dir = run_dir.join("cores");
+    if !cores_dir.is_dir() {
+        warn!("No cores directory in {:?}", run_dir);
+        return Ok(measurements)
+    }
+    for file in fs::read_dir(&cores_dir).map_err(Error::io(&cores_dir))? {
+        let path = file.map_err(Error::io(&cores_dir))?.path();
+        let fname = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let pair = fname.strip_prefix("cores_")
+            .and_then(|p| p.split_once('_'))
+            .and_then(|(a, b)| Some((a.parse::<u16>().ok()?, b.parse::<u16>().ok()?)));
+        if let Some(pair) = pair {
+            measurements.insert(pair, Event::from_csv(path.as_path(), nodeid_length)?);
+        }
+    }
+    Ok(measurements)
+}
+
+/// Sum p0/p1 flits per port, hottest first
+///  `port_events` holds the (type, event ID) of the p0 and p1 variant of an event, e.g. mxp_p0/p1_dat_txflit_valid
+pub fn hottest_ports(events: &[Event], port_events: &[(u8, u16); 2]) -> Vec<(PortLocation, i128)> {
+    let mut counts: HashMap<PortLocation, i128> = HashMap::new();
+    for e in events.iter().filter(|e| e.counts > 0) {
+        if let Some(port) = port_events.iter().position(|p| *p == (e.event_type, e.event_id)) {
+            *counts.entry((e.node_id.x, e.node_id.y, port as u16)).or_default() += e.counts;
+        }
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+/// Two hottest ports of every core measurement of one CMN directory, keyed by the core pair
+pub fn measured_hot_ports(run_dir: &Path, nodeid_length: u8, port_events: &[(u8, u16); 2])
+                          -> Result<BTreeMap<(u16, u16), BTreeSet<PortLocation>>> {
+    Ok(read_core_measurements(run_dir, nodeid_length)?
+        .iter()
+        .map(|(pair, events)| (*pair, hottest_ports(events, port_events).into_iter().take(2).map(|(l, _)| l).collect()))
+        .collect())
+}
+
+/// Placement of a core derived from the hot ports of its measurements
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CorePlacement {
+    /// Exactly one port fits all measurements of the core
+    Unique(PortLocation),
+    /// Several ports fit all measurements of the core
+    Ambiguous(BTreeSet<PortLocation>),
+    /// No port fits all measurements of the core
+    Contradictory,
+}
+
+/// Place every measured core, given the two hottest ports of each measurement (keyed by the core pair)
+///  Every measurement lights up the ports of both its cores, so a core sits on one of the two hottest ports of each
+///   measurement it took part in. Once a core has been placed uniquely, its port is removed from the hot ports of its
+///   measurements, which narrows down the placement of its partners; this is repeated until nothing changes.
+///  With a single reference core (default sweep), the reference core is placed first and then every partner core.
+///  Only the hot ports are considered, not their counts: if a third port (e.g. the HN-F of the line) is busier than
+///   one of the cores' ports, a core may be placed on it, or end up ambiguous or contradictory.
+pub fn place_cores(hot_ports: &BTreeMap<(u16, u16), BTreeSet<PortLocation>>) -> BTreeMap<u16, CorePlacement> {
+    let mut candidates: BTreeMap<u16, BTreeSet<PortLocation>> = BTreeMap::new();
+    for ((a, b), ports) in hot_ports {
+        for core in [a, b] {
+            candidates.entry(*core)
+                .and_modify(|c| c.retain(|l| ports.contains(l)))
+                .or_insert(ports.clone());
+        }
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for ((a, b), ports) in hot_ports {
+            for (placed, other) in [(a, b), (b, a)] {
+                let Some(location) = single(&candidates[placed]) else { continue };
+                let remaining: BTreeSet<PortLocation> = ports.iter().filter(|l| **l != location).copied().collect();
+                let other_candidates = candidates.get_mut(other).unwrap();
+                let narrowed: BTreeSet<PortLocation> = other_candidates.intersection(&remaining).copied().collect();
+                if !narrowed.is_empty() && narrowed.len() < other_candidates.len() {
+                    *other_candidates = narrowed;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    candidates.into_iter().map(|(core, locations)| {
+        let placement = match (single(&locations), locations.is_empty()) {
+            (Some(location), _) => CorePlacement::Unique(location),
+            (None, false) => CorePlacement::Ambiguous(locations),
+            (None, true) => CorePlacement::Contradictory
+        };
+        (core, placement)
+    }).collect()
+}
+
+/// Assign logical CPUs to ports, see `place_cores`
+///  Ambiguous cores are assumed to sit on the first of their candidate ports, contradictory ones are left out.
+///  All CPUs of the core's cluster share its port; without a known cluster, cores_per_dsu consecutive CPUs are assumed.
+fn place_cpus(run_dir: &Path, nodeid_length: u8, clusters: &[Vec<u16>], cores_per_dsu: u16,
+              port_events: &[(u8, u16); 2]) -> Result<BTreeMap<PortLocation, Vec<u16>>> {
+    let dsu_of = |core: u16| clusters.iter()
+        .find(|c| c.contains(&core))
+        .cloned()
+        .unwrap_or((core..core + cores_per_dsu).collect());
+
+    let mut placement: BTreeMap<PortLocation, Vec<u16>> = BTreeMap::new();
+    for (core, core_placement) in place_cores(&measured_hot_ports(run_dir, nodeid_length, port_events)?) {
+        match core_placement {
+            CorePlacement::Unique(location) => placement.entry(location).or_default().extend(dsu_of(core)),
+            CorePlacement::Ambiguous(locations) => {
+                let location = *locations.first().unwrap();
+                warn!("Placement of core {core} is ambiguous ({:?}), assuming {:?}", locations, location);
+                placement.entry(location).or_default().extend(dsu_of(core));
+            },
+            CorePlacement::Contradictory => warn!("Could not place core {core}: measurements contradict each other")
+        }
+    }
+    if placement.is_empty() {
+        warn!("No core measurements with data flits found, cannot place any CPU");
+    }
+    placement.values_mut().for_each(|cpus| { cpus.sort(); cpus.dedup(); });
+    Ok(placement)
+}
+
+fn single(locations: &BTreeSet<PortLocation>) -> Option<PortLocation> {
+    match locations.len() {
+        1 => locations.first().copied(),
+        _ => None
+    }
+}
+
+/// Assign HN-Fs and memory controllers to the NUMA nodes measured by determine-memory (`memory/numa_K.csv`)
+///  An HN-F serves a node if it sent at least MEMORY_ACTIVITY_THRESHOLD of the memory requests of the busiest HN-F.
+///  Ports without an observable node (XP in the topology) that received requests likewise are taken as its SN-Fs.
+fn map_memory(run_dir: &Path, cmn_dir: &Path, topology: &Topology) -> Result<MemoryMap> {
+    let events_map = read_events_map(run_dir.join("events.csv").as_path())?;
+    let hnf_event = get_event(&events_map, HNF_MC_EVENT)?;
+    let port_events = [get_event(&events_map, PORT_REQ_EVENTS[0])?, get_event(&events_map, PORT_REQ_EVENTS[1])?];
+    let layout: NumaLayout = read_json(cmn_dir.join("numa.json").as_path())?
+        .ok_or_else(|| Error::Config(format!("No numa.json in {}, memory was measured without it", cmn_dir.display())))?;
+    let port_type = |(x, y, port): PortLocation| topology.xps.iter()
+        .find(|xp| xp.x == x && xp.y == y)
+        .and_then(|xp| xp.ports.iter().find(|p| p.port == port))
+        .map(|p| p.node_type);
+    let active = |counts: Vec<(PortLocation, i128)>| -> Vec<PortLocation> {
+        let max_counts = counts.iter().map(|(_, c)| *c).max().unwrap_or(0);
+        let mut ports: Vec<PortLocation> = counts.into_iter()
+            .filter(|(_, c)| *c > 0 && *c as f64 >= max_counts as f64 * MEMORY_ACTIVITY_THRESHOLD)
+            .map(|(l, _)| l)
+            .collect();
+        ports.sort();
+        ports
+    };
+
+    let mut nodes = Vec::new();
+    for node in layout.nodes {
+        let path = cmn_dir.join(format!("memory/numa_{}.csv", node.node));
+        if !path.is_file() {
+            warn!("No memory measurement for NUMA node {} in {:?}", node.node, cmn_dir);
+            continue
+        }
+        let events = Event::from_csv(path.as_path(), topology.nodeid_length)?;
+
+        let hnfs = active(events.iter()
+            .filter(|e| (e.event_type, e.event_id) == hnf_event)
+            .map(|e| ((e.node_id.x, e.node_id.y, e.node_id.port), e.counts))
+            .collect());
+        let memory_controllers = active(hottest_ports(&events, &port_events).into_iter()
+            .filter(|(l, _)| port_type(*l) == Some(NodeType::XpOnly))
+            .collect());
+        if hnfs.is_empty() || memory_controllers.is_empty() {
+            warn!("NUMA node {}: {} HN-Fs and {} memory controllers found", node.node, hnfs.len(), memory_controllers.len());
+        }
+        if let Some(hnf) = hnfs.iter().find(|l| port_type(**l) != Some(NodeType::HnF)) {
+            warn!("Port {:?} sent memory requests for NUMA node {}, but is not classified as HN-F", hnf, node.node);
+        }
+        info!("NUMA node {}: {} HN-Fs, memory controllers at {:?}", node.node, hnfs.len(), memory_controllers);
+        nodes.push(NumaMemory { node: node.node, cpus: node.cpus, hnfs, memory_controllers });
+    }
+    Ok(MemoryMap { cmn_idx: topology.cmn_idx, numa_config: layout.numa_config, nodes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: PortLocation = (0, 0, 0);
+    const B: PortLocation = (1, 0, 1);
+    const C: PortLocation = (2, 1, 0);
+    /// HN-F of the bounced line, busier than one of the cores' ports
+    const H: PortLocation = (1, 1, 1);
+
+    fn hot_ports(measurements: &[((u16, u16), &[PortLocation])]) -> BTreeMap<(u16, u16), BTreeSet<PortLocation>> {
+        measurements.iter().map(|(pair, ports)| (*pair, ports.iter().copied().collect())).collect()
+    }
+
+    #[test]
+    fn run_topology() {
+        let run_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/run_3x2");
+        let topology = build_topology(run_dir.as_path(), 0, None, None).unwrap();
+        assert_eq!((topology.nodeid_length, topology.mesh_x, topology.mesh_y, topology.xps.len()), (7, 3, 2, 6));
+
+        let ports: BTreeMap<PortLocation, (NodeType, Vec<u16>, Vec<NodeType>)> = topology.xps.iter()
+            .flat_map(|xp| xp.ports.iter().map(move |p| ((xp.x, xp.y, p.port), p)))
+            .filter(|(_, p)| p.node_type != NodeType::XpOnly)
+            .map(|(l, p)| (l, (p.node_type, p.cpus.clone(), p.conflicting_types.clone())))
+            .collect();
+        assert_eq!(ports, BTreeMap::from([
+            ((0, 0, 0), (NodeType::RnF, vec![0, 1], vec![])),
+            ((0, 0, 1), (NodeType::RnF, vec![2, 3], vec![])),
+            ((0, 1, 1), (NodeType::HnI, vec![], vec![])),
+            ((1, 0, 0), (NodeType::HnF, vec![], vec![])),
+            ((1, 1, 0), (NodeType::HnF, vec![], vec![])),
+            // both an HN-F and placed cores, the conflict is kept
+            ((2, 0, 0), (NodeType::RnF, vec![4, 5], vec![NodeType::HnF])),
+            ((2, 1, 1), (NodeType::RnD, vec![], vec![])),
+        ]));
+    }
+
+    #[test]
+    fn reference_sweep() {
+        let placement = place_cores(&hot_ports(&[((0, 4), &[A, B]), ((0, 8), &[A, C]), ((0, 12), &[A, H])]));
+        assert_eq!(placement, BTreeMap::from([(0, CorePlacement::Unique(A)), (4, CorePlacement::Unique(B)),
+                                              (8, CorePlacement::Unique(C)), (12, CorePlacement::Unique(H))]));
+    }
+
+    #[test]
+    fn pairs_sweep_shared_port() {
+        // core 4 shares the reference core's port, so its measurement with the reference core only lights up one port
+        //  of the two, the pairs with core 8 still place it there
+        let placement = place_cores(&hot_ports(&[((0, 4), &[A, H]), ((0, 8), &[A, C]), ((4, 8), &[A, C])]));
+        assert_eq!(placement, BTreeMap::from([(0, CorePlacement::Unique(A)), (4, CorePlacement::Unique(A)),
+                                              (8, CorePlacement::Unique(C))]));
+    }
+
+    #[test]
+    fn ambiguous() {
+        let placement = place_cores(&hot_ports(&[((0, 4), &[A, B])]));
+        let both = CorePlacement::Ambiguous(BTreeSet::from([A, B]));
+        assert_eq!(placement, BTreeMap::from([(0, both.clone()), (4, both)]));
+    }
+
+    #[test]
+    fn contradictory() {
+        // the reference core cannot sit on a port of both measurements
+        let placement = place_cores(&hot_ports(&[((0, 4), &[A, B]), ((0, 8), &[C, H])]));
+        assert_eq!(placement[&0], CorePlacement::Contradictory);
+        assert_eq!(placement[&4], CorePlacement::Ambiguous(BTreeSet::from([A, B])));
+        assert_eq!(placement[&8], CorePlacement::Ambiguous(BTreeSet::from([C, H])));
+    }
+}
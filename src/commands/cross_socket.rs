@@ -0,0 +1,122 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use log::{info, warn};
+
+use crate::commands::determine_nodes::probe_gateways;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::perf::PerfBackend;
+use crate::topology::MeshInfo;
+use crate::utils::{cmn_folder, get_event_type_id, parse_event_type_id};
+use crate::writer::Writer;
+
+/// Channels whose flits are reported per gateway: requests and data make up the bulk of cross-socket traffic
+static CHANNELS: [&str; 2] = ["req", "dat"];
+/// Outgoing directions of an XP, in the same order as their offset in `neighbour`
+static DIRECTIONS: [&str; 4] = ["n", "e", "s", "w"];
+/// CXS flits the link agent of a gateway received from and sent to the other socket, only exposed for CMN-700 (CCLA)
+///  The CXRA/CXHA events of CMN-600 gateways only count tracker occupancy and stalls, not flits.
+static LINK_EVENTS: [&str; 2] = ["ccla_rx_cxs", "ccla_tx_cxs"];
+
+/// Flits sent via every link and port of every XP, for all reported channels
+fn mxp_events() -> Vec<String> {
+    CHANNELS.iter()
+        .flat_map(|channel| DIRECTIONS.iter().chain(["p0", "p1"].iter())
+            .map(move |direction| format!("mxp_{direction}_{channel}_txflit_valid")))
+        .collect()
+}
+
+/// Events of the `cross-socket` preset: the XP flits, and the link agent flits on both ports if the CMN exposes them
+pub fn preset_events(cmn_idx: Option<u8>) -> Vec<String> {
+    let mut events = mxp_events();
+    if cmn_idx.is_some_and(|cmn_idx| LINK_EVENTS.iter().all(|name| get_event_type_id(cmn_idx, name).is_ok())) {
+        events.extend(LINK_EVENTS.iter().map(|name| format!("01:{name}")));
+    }
+    events
+}
+
+/// Per CMN, the XPs with a CCIX gateway and the ports the gateways are attached to
+pub type Gateways = BTreeMap<u8, BTreeMap<(u16, u16), BTreeSet<u16>>>;
+
+/// Locate the CCIX gateways of every CMN
+pub fn locate_gateways(meshes: &[MeshInfo], perf: &dyn PerfBackend) -> Result<Gateways> {
+    meshes.iter().map(|mesh| {
+        let mut gateways: BTreeMap<(u16, u16), BTreeSet<u16>> = BTreeMap::new();
+        for e in probe_gateways(mesh, perf)?.into_iter().filter(|e| e.counts >= 0) {
+            gateways.entry((e.node_id.x, e.node_id.y)).or_default().insert(e.node_id.port);
+        }
+        if gateways.is_empty() {
+            warn!("No CCIX gateways found on CMN {}, is this a multi-socket system?", mesh.cmn_idx);
+        }
+        Ok((mesh.cmn_idx, gateways))
+    }).collect()
+}
+
+/// Event type and ID of the given event, as exposed by the CMN PMU
+fn event_id(cmn_idx: u8, name: &str) -> Result<(u8, u16)> {
+    let type_id = get_event_type_id(cmn_idx, name)?;
+    parse_event_type_id(type_id.as_str()).ok_or_else(|| Error::parse("event type and ID", type_id.as_str()))
+}
+
+/// Write the egress and ingress flits of every gateway XP as `cmn_N/gateways.csv`
+///  Egress are the flits the XP sent to its gateway port(s), i.e. towards the other socket.
+///  Ingress is counted by the gateway's link agent where the CMN exposes its events (`cxs` rows, `counted`). The XPs
+///   only count sent flits, so otherwise the flits leaving the XP minus those entering it from its neighbours are
+///   reported, which include the ingress but also whatever a device on a non-gateway port injects (`upper_bound`).
+pub fn write_report(mesh: &MeshInfo, gateways: &BTreeMap<(u16, u16), BTreeSet<u16>>, events: &[Event], writer: &Writer)
+                    -> Result<()> {
+    let event_ids = mxp_events().into_iter()
+        .map(|name| event_id(mesh.cmn_idx, name.as_str()).map(|id| (name, id)))
+        .collect::<Result<HashMap<String, (u8, u16)>>>()?;
+    let matching = |(event_type, event_id): (u8, u16), x: u16, y: u16| events.iter()
+        .filter(move |e| e.event_type == event_type && e.event_id == event_id && e.node_id.x == x && e.node_id.y == y);
+    let count = |name: String, x: u16, y: u16| -> i128 {
+        matching(event_ids[&name], x, y).map(|e| e.counts.max(0)).sum()
+    };
+    // link agent flits on the gateway ports, None if not exposed or not counted
+    let link_ids: Vec<Option<(u8, u16)>> = LINK_EVENTS.iter().map(|name| event_id(mesh.cmn_idx, name).ok()).collect();
+    let count_link = |event: usize, x: u16, y: u16, ports: &BTreeSet<u16>| -> Option<i128> {
+        let counted: Vec<i128> = matching(link_ids[event]?, x, y)
+            .filter(|e| ports.contains(&e.node_id.port) && e.counts >= 0)
+            .map(|e| e.counts)
+            .collect();
+        (!counted.is_empty()).then(|| counted.iter().sum())
+    };
+    // neighbour in each direction and the direction it sends towards this XP
+    let neighbour = |x: u16, y: u16, direction: usize| -> Option<(u16, u16, &str)> {
+        match direction {
+            0 if y + 1 < mesh.mesh_y => Some((x, y + 1, "s")),
+            1 if x + 1 < mesh.mesh_x => Some((x + 1, y, "w")),
+            2 if y > 0 => Some((x, y - 1, "n")),
+            3 if x > 0 => Some((x - 1, y, "e")),
+            _ => None
+        }
+    };
+
+    let mut lines = vec![String::from("x;y;ports;channel;egress;ingress;ingress_kind")];
+    for ((x, y), ports) in gateways {
+        let received_cxs = count_link(0, *x, *y, ports);
+        let sent_cxs = count_link(1, *x, *y, ports);
+        let port_list = ports.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(",");
+        for channel in CHANNELS {
+            let port_counts: Vec<i128> = (0..=1).map(|p| count(format!("mxp_p{p}_{channel}_txflit_valid"), *x, *y)).collect();
+            let egress: i128 = ports.iter().map(|p| port_counts[*p as usize]).sum();
+            let sent: i128 = DIRECTIONS.iter().map(|d| count(format!("mxp_{d}_{channel}_txflit_valid"), *x, *y)).sum::<i128>()
+                + port_counts.iter().sum::<i128>();
+            let received: i128 = (0..DIRECTIONS.len())
+                .filter_map(|direction| neighbour(*x, *y, direction))
+                .map(|(nx, ny, d)| count(format!("mxp_{d}_{channel}_txflit_valid"), nx, ny))
+                .sum();
+            let ingress_upper_bound = (sent - received).max(0);
+            if received_cxs.is_none() {
+                info!("CMN {} gateway ({x},{y}) {channel}: {egress} flits egress, at most {ingress_upper_bound} flits \
+                       ingress", mesh.cmn_idx);
+            }
+            lines.push(format!("{x};{y};{port_list};{channel};{egress};{ingress_upper_bound};upper_bound"));
+        }
+        if let (Some(ingress), Some(egress)) = (received_cxs, sent_cxs) {
+            info!("CMN {} gateway ({x},{y}) cxs: {egress} flits egress, {ingress} flits ingress", mesh.cmn_idx);
+            lines.push(format!("{x};{y};{port_list};cxs;{egress};{ingress};counted"));
+        }
+    }
+    writer.write_lines(lines, "gateways.csv", Some(cmn_folder(mesh.cmn_idx).as_str()))
+}
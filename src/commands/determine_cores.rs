@@ -1,62 +1,216 @@
-use std::process::Command;
-use log::{debug, info};
-use std::fs;
+use std::collections::BTreeSet;
+use log::{debug, info, warn};
 use std::io::Write;
-use std::path::{Path};
+use std::process::Command;
 
-use crate::utils::{get_event_string, get_event_type_id, perf_to_event_vec};
+use crate::args::{CoreSweep, DetermineTopologyArgs};
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::perf::PerfBackend;
+use crate::utils::{benchmark_command, cmn_folder, get_clusters, get_cmn_cpus, get_event_string, get_event_type_id, parse_event_type_id};
 use crate::writer::Writer;
 
+/// Data flits sent via the links to the neighbouring XPs
+pub static LINK_EVENTS: [&str; 4] = ["mxp_n_dat_txflit_valid", "mxp_e_dat_txflit_valid",
+                                     "mxp_s_dat_txflit_valid", "mxp_w_dat_txflit_valid"];
+
+/* Defaults of the benchmark binary (src/benchmark/main.rs), needed to normalize its runtime if not given explicitly */
+static BENCHMARK_DEFAULT_NUM_ITERATIONS: u64 = 50000;
+static BENCHMARK_DEFAULT_NUM_SAMPLES: u64 = 5000;
+
+/// Latency statistics printed by the benchmark binary with `--format json`, in this order in latency_stats.csv
+static LATENCY_STATS: [&str; 7] = ["samples", "round_trips", "min_ns", "median_ns", "mean_ns", "p99_ns", "stddev_ns"];
+
 /// Determine position of cores / DSUs throughout CMN
 ///  Observe MXP p0/p1 data flits while a custom benchmark (src/benchmark/benchmark.rs) is running on two cores which causes
 ///   cache line transmissions between both cores.
 ///  This causes p0/p1 to "light up" on an otherwise quiet system
-pub fn determine(nodeid_length: u8, mesh_size: (u16, u16), cores_per_dsu: u16,
-                 benchmark_binary_path: &Path, benchmark_binary_args: Option<Vec<String>>,
-                 writer: &Writer) {
-    info!("Determining Cores");
-
-    let num_procs = fs::read_to_string("/proc/cpuinfo").unwrap()
-        .split("\n")
-        .filter(|l| l.starts_with("processor"))
-        .map(|l| l.split(":").skip(1).next().unwrap().trim())
-        .map(|x| x.parse::<u16>().unwrap())
-        .last()
-        .unwrap() + 1;
-    let num_dsus = num_procs / cores_per_dsu;
+///  By default, the first core attached to the CMN is used as reference core and paired with the first core of every other DSU.
+///  As the reference core's port lights up in every measurement, the pairs sweep pairs DSUs with each other instead,
+///   which makes the placement unambiguous even if a DSU shares the reference core's XP.
+///  DSUs are taken from the CPU clusters in sysfs, unless cores_per_dsu is given
+///  The benchmark reports latency statistics over its samples, the median is recorded in latency.csv (so samples
+///   disturbed by interrupts do not skew it), all statistics in latency_stats.csv
+///  With benchmark_duration, every pair runs for the same time, and the flits are normalized to the number of round trips
+///   completed (flits_per_round_trip.csv), so the counts of near and far pairs can be compared
+pub fn determine(cmn_idx: u8, nodeid_length: u8, mesh_size: (u16, u16), cores_per_dsu: Option<u16>,
+                 args: &DetermineTopologyArgs, perf: &dyn PerfBackend, writer: &Writer) -> Result<()> {
+    info!("Determining Cores of CMN {cmn_idx}");
+
+    let clusters = get_clusters(&get_cmn_cpus(cmn_idx)?, cores_per_dsu);
+    writer.write_json(&clusters, "clusters.json", Some(cmn_folder(cmn_idx).as_str()))?;
+    let dsu_cores: Vec<u16> = clusters.iter().map(|c| c[0]).collect();
+    if dsu_cores.is_empty() {
+        warn!("No CPUs found for CMN {cmn_idx}, skipping");
+        return Ok(())
+    }
+    debug!("{} DSUs, sizes: {:?}", dsu_cores.len(), clusters.iter().map(|c| c.len()).collect::<Vec<_>>());
+    let pairs = get_core_pairs(&dsu_cores, args.core_sweep, &args.dsu_pairs)?;
+    let num_pairs = pairs.len();
+    let mut benchmark_binary_args = args.benchmark_binary_args.clone();
+    if !benchmark_binary_args.as_ref().is_some_and(|a| a.iter().any(|a| a == "--format")) && supports_format(args) {
+        benchmark_binary_args.get_or_insert_with(Vec::new).extend([String::from("--format"), String::from("json")]);
+    }
+    if let Some(duration) = args.benchmark_duration {
+        benchmark_binary_args.get_or_insert_with(Vec::new).extend([String::from("--duration"), duration.to_string()]);
+    }
+    let folder = format!("{}/cores", cmn_folder(cmn_idx));
+    let num_round_trips = get_benchmark_arg(&benchmark_binary_args, "--num-iterations")
+                                .unwrap_or(BENCHMARK_DEFAULT_NUM_ITERATIONS)
+        * get_benchmark_arg(&benchmark_binary_args, "--num-samples").unwrap_or(BENCHMARK_DEFAULT_NUM_SAMPLES);
+    let mut latencies = Vec::new();
+    let mut latency_stats = vec![format!("core_a;core_b;{}", LATENCY_STATS.join(";"))];
+    let mut flits = vec![String::from("core_a;core_b;event;x;y;flits_per_round_trip")];
+    let mut event_names = vec!["mxp_p0_dat_txflit_valid", "mxp_p1_dat_txflit_valid"];
+    if args.record_links {
+        event_names.extend(LINK_EVENTS);
+    }
+    let event_types = event_names.iter()
+        .map(|name| get_event_type_id(cmn_idx, name))
+        .collect::<Result<Vec<_>>>()?;
+    let event_ids = event_types.iter()
+        .map(|type_id| parse_event_type_id(type_id).ok_or_else(|| Error::parse("event type and ID", type_id.as_str())))
+        .collect::<Result<Vec<_>>>()?;
     debug!("Getting placements of DSUs");
-    for n in 1..num_dsus {
-        print!("\r[{n}/{num_dsus}]");
-        std::io::stdout().flush().expect("Could not flush stdout");
+    for (n, (reference_core, core)) in pairs.into_iter().enumerate() {
+        print!("\r[{}/{num_pairs}]", n + 1);
+        let _ = std::io::stdout().flush();
         
         let mut events = Vec::new();
         for i in 0..mesh_size.0 {
             for j in 0..mesh_size.1 {
-                events.push(String::from("-e"));
-                events.push(get_event_string(0, i, j, 0, nodeid_length,
-                                             &get_event_type_id("mxp_p0_dat_txflit_valid")));
-                events.push(String::from("-e"));
-                events.push(get_event_string(0, i, j, 0, nodeid_length,
-                                             &get_event_type_id("mxp_p1_dat_txflit_valid")));
+                for event_type in &event_types {
+                    events.push(get_event_string(cmn_idx, i, j, 0, nodeid_length, event_type));
+                }
             }
         }
 
-        let mut cmd = Command::new("perf");
-
-        cmd.arg("stat")
-            .arg("--field-separator")
-            .arg(";")
-            .args(events)
-            .arg(benchmark_binary_path);
+        let mut benchmark = benchmark_command(args)?;
         if let Some(benchmark_binary_args) = benchmark_binary_args.clone() {
-            cmd.args(benchmark_binary_args);
+            benchmark.args(benchmark_binary_args);
         }
-        cmd.arg("--cores")
-           .arg(format!("0,{}", cores_per_dsu*n));
+        benchmark.arg("--cores")
+           .arg(format!("{reference_core},{core}"));
 
-        let output =  String::from_utf8(cmd.output().unwrap().stderr).unwrap();
-        let parsed_output = perf_to_event_vec(output.as_str(), nodeid_length);
-        writer.write_events(&parsed_output, format!("cores_0_{}", n*cores_per_dsu).as_str(), Some("cores"));
+        let (counts, cmd_output) = perf.stat(&events, benchmark, None)?;
+        let parsed_output = counts.to_events(nodeid_length)?;
+        writer.write_events(&parsed_output, format!("cores_{reference_core}_{core}").as_str(), Some(folder.as_str()))?;
+
+        // benchmark prints latency statistics as JSON, or without --format the total runtime in microseconds
+        //  (the number of round trips with --duration)
+        let stdout = String::from_utf8_lossy(&cmd_output.stdout);
+        let stats = serde_json::from_str::<serde_json::Value>(stdout.trim()).ok()
+            .filter(|stats| stats.get("median_ns").is_some_and(|m| m.is_f64()));
+        let round_trips = match (stats, stdout.trim().parse::<f64>(), args.benchmark_duration) {
+            (Some(stats), _, _) => {
+                latencies.push((reference_core, core, stats["median_ns"].as_f64().unwrap()));
+                let values = LATENCY_STATS.iter()
+                    .map(|s| stats.get(*s).map(|v| v.to_string()).unwrap_or_default())
+                    .collect::<Vec<_>>();
+                latency_stats.push(format!("{reference_core};{core};{}", values.join(";")));
+                stats["round_trips"].as_u64()
+            }
+            (None, Ok(round_trips), Some(_)) => Some(round_trips as u64),
+            (None, Ok(micros), None) => {
+                latencies.push((reference_core, core, micros * 1000.0 / num_round_trips as f64));
+                Some(num_round_trips)
+            }
+            _ => {
+                warn!("Could not parse benchmark output for cores {reference_core},{core}: `{}`", stdout.trim());
+                None
+            }
+        };
+        match round_trips.filter(|r| *r > 0) {
+            Some(round_trips) => flits.extend(flits_per_round_trip(&parsed_output, &event_names, &event_ids,
+                                                                   round_trips)
+                .into_iter()
+                .map(|l| format!("{reference_core};{core};{l}"))),
+            None => warn!("Number of round trips unknown for cores {reference_core},{core}, not normalizing flits")
+        }
     }
     println!(); // newline to end \r shenanigans at start of loop
+
+    writer.write_lines(latency_matrix(&latencies), "latency.csv", Some(folder.as_str()))?;
+    if latency_stats.len() > 1 {
+        writer.write_lines(latency_stats, "latency_stats.csv", Some(folder.as_str()))?;
+    }
+    writer.write_lines(flits, "flits_per_round_trip.csv", Some(folder.as_str()))
+}
+
+/// `event;x;y;flits_per_round_trip` for every XP event that counted flits
+fn flits_per_round_trip(events: &[Event], event_names: &[&str], event_ids: &[(u8, u16)], round_trips: u64)
+                        -> Vec<String> {
+    events.iter()
+        .filter(|e| e.counts > 0)
+        .filter_map(|e| {
+            let name = event_names[event_ids.iter().position(|id| *id == (e.event_type, e.event_id))?];
+            Some(format!("{name};{};{};{:.4}", e.node_id.x, e.node_id.y, e.counts as f64 / round_trips as f64))
+        })
+        .collect()
+}
+
+/// Core pairs to run the benchmark on, using the first core of each DSU
+fn get_core_pairs(dsu_cores: &[u16], core_sweep: CoreSweep, dsu_pairs: &Option<Vec<String>>)
+                  -> Result<Vec<(u16, u16)>> {
+    let dsu_core = |dsu: usize| dsu_cores.get(dsu).copied()
+        .ok_or_else(|| Error::Config(format!("DSU {dsu} does not exist ({} DSUs)", dsu_cores.len())));
+    match (core_sweep, dsu_pairs) {
+        // main rejects dsu_pairs without the pairs sweep
+        (CoreSweep::Reference, _) => Ok(dsu_cores.iter().skip(1).map(|core| (dsu_cores[0], *core)).collect()),
+        (CoreSweep::Pairs, Some(dsu_pairs)) => dsu_pairs.iter().map(|pair| {
+            let (a, b) = pair.split_once(':')
+                .and_then(|(a, b)| Some((a.parse::<usize>().ok()?, b.parse::<usize>().ok()?)))
+                .ok_or_else(|| Error::Config(format!("Invalid DSU pair `{pair}`, expected `a:b`")))?;
+            if a == b {
+                return Err(Error::Config(format!("DSU pair `{pair}` pairs a DSU with itself")))
+            }
+            Ok((dsu_core(a)?, dsu_core(b)?))
+        }).collect(),
+        (CoreSweep::Pairs, None) => Ok(dsu_cores.iter().enumerate()
+            .flat_map(|(i, a)| dsu_cores.iter().skip(i + 1).map(move |b| (*a, *b)))
+            .collect())
+    }
+}
+
+/// Whether the benchmark prints latency statistics with `--format json`
+///  The embedded benchmark always does, an external binary only if its `--help` lists the option (older ones only print
+///   their runtime, and fail on unknown arguments).
+fn supports_format(args: &DetermineTopologyArgs) -> bool {
+    let Some(path) = &args.benchmark_binary_path else {
+        return true
+    };
+    let supported = Command::new(path).arg("--help").output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("--format"));
+    if !supported {
+        info!("{path} does not support --format, using the runtime it reports");
+    }
+    supported
+}
+
+/// Get numeric value of a `--arg value` style argument of the benchmark binary
+fn get_benchmark_arg(benchmark_binary_args: &Option<Vec<String>>, arg: &str) -> Option<u64> {
+    let args = benchmark_binary_args.as_ref()?;
+    let pos = args.iter().position(|a| a == arg)?;
+    args.get(pos + 1)?.parse().ok()
+}
+
+/// Format core pair latencies (in ns per round trip) as symmetric matrix, unmeasured pairs are left empty
+pub fn latency_matrix(latencies: &[(u16, u16, f64)]) -> Vec<String> {
+    let cores: Vec<u16> = latencies.iter()
+        .flat_map(|(a, b, _)| [*a, *b])
+        .collect::<BTreeSet<u16>>()
+        .into_iter()
+        .collect();
+
+    let mut lines = vec![format!("core;{}", cores.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(";"))];
+    for a in &cores {
+        let row = cores.iter().map(|b| {
+            latencies.iter()
+                .find(|(x, y, _)| (x == a && y == b) || (x == b && y == a))
+                .map(|(_, _, l)| format!("{l:.2}"))
+                .unwrap_or_default()
+        }).collect::<Vec<_>>();
+        lines.push(format!("{a};{}", row.join(";")));
+    }
+    lines
 }
\ No newline at end of file
@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use log::{debug, info, warn};
+
+use crate::args::DetermineTopologyArgs;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::perf::PerfBackend;
+use crate::utils::{benchmark_command, cmn_folder, get_cmn_cpus, get_event_string, get_event_type_id, parse_event_type_id};
+use crate::writer::Writer;
+
+/// SLC and snoop filter lookups, counted by the HN-F serving the requested line
+static HNF_ACCESS_EVENT: &str = "hnf_slc_sf_cache_access";
+/// Flush and load iterations per cache line, enough to stand out from background traffic
+static HASH_LINE_ITERATIONS: u32 = 100000;
+
+/// Recover the mapping of physical addresses to HN-Fs (SLC slices)
+///  For every cache line, the benchmark repeatedly flushes and loads it and prints its physical address (via pagemap),
+///   while the SLC accesses of every HN-F found by determine_nodes are recorded. The line is attributed to the HN-F with
+///   the most accesses, which yields one `physical_address;x;y;port` entry of the hash table `cmn_N/slc_hash.csv`.
+pub fn determine(cmn_idx: u8, nodeid_length: u8, nodes: &[Event], args: &DetermineTopologyArgs, perf: &dyn PerfBackend,
+                 writer: &Writer) -> Result<()> {
+    info!("Determining SLC address hashing of CMN {cmn_idx}");
+
+    let hnf_event = get_event_type_id(cmn_idx, "hnf_seq_full")?;
+    let (hnf_type, _) = parse_event_type_id(hnf_event.as_str()).ok_or_else(|| Error::parse("event", hnf_event.as_str()))?;
+    let hnfs: Vec<(u16, u16, u16)> = nodes.iter()
+        .filter(|e| e.event_type == hnf_type && e.counts >= 0)
+        .map(|e| (e.node_id.x, e.node_id.y, e.node_id.port))
+        .collect();
+    let Some(core) = get_cmn_cpus(cmn_idx)?.first().copied() else {
+        warn!("No CPUs found for CMN {cmn_idx}, skipping");
+        return Ok(())
+    };
+    if hnfs.is_empty() {
+        warn!("No HN-Fs found on CMN {cmn_idx}, skipping");
+        return Ok(())
+    }
+    debug!("{} HN-Fs, touching lines from core {core}", hnfs.len());
+
+    let access_event = get_event_type_id(cmn_idx, HNF_ACCESS_EVENT)?;
+    let mut events = Vec::new();
+    for (x, y, port) in &hnfs {
+        events.push(get_event_string(cmn_idx, *x, *y, *port, nodeid_length, access_event.as_str()));
+    }
+
+    let folder = format!("{}/slc_hash", cmn_folder(cmn_idx));
+    let mut lines = vec![String::from("line;physical_address;x;y;port;share")];
+    let mut lines_per_hnf: BTreeMap<(u16, u16, u16), usize> = BTreeMap::new();
+    for line in 0..args.hash_lines {
+        print!("\r[{}/{}]", line + 1, args.hash_lines);
+        let _ = std::io::stdout().flush();
+
+        let mut benchmark = benchmark_command(args)?;
+        benchmark.arg("--hash-line")
+            .arg(line.to_string())
+            .arg("--num-iterations")
+            .arg(HASH_LINE_ITERATIONS.to_string())
+            .arg("--cores")
+            .arg(core.to_string());
+        let (counts, cmd_output) = perf.stat(&events, benchmark, None)?;
+        let parsed_output = counts.to_events(nodeid_length)?;
+        writer.write_events(&parsed_output, format!("line_{line}").as_str(), Some(folder.as_str()))?;
+
+        // benchmark prints the physical address of the line on stdout, nothing if it could not be translated
+        let stdout = String::from_utf8_lossy(&cmd_output.stdout);
+        let Ok(physical_address) = u64::from_str_radix(stdout.trim().trim_start_matches("0x"), 16) else {
+            warn!("Could not get physical address of line {line} (needs root): `{}`", stdout.trim());
+            continue
+        };
+        let total: i128 = parsed_output.iter().map(|e| e.counts.max(0)).sum();
+        let Some(hottest) = parsed_output.iter().max_by_key(|e| e.counts) else { continue };
+        let share = hottest.counts as f64 / total.max(1) as f64;
+        if share < 0.5 {
+            warn!("Line {line} ({physical_address:#x}) is ambiguous, hottest HN-F saw only {:.0}% of accesses", share * 100.0);
+        }
+        let hnf = (hottest.node_id.x, hottest.node_id.y, hottest.node_id.port);
+        *lines_per_hnf.entry(hnf).or_default() += 1;
+        lines.push(format!("{line};{physical_address:#x};{};{};{};{share:.2}", hnf.0, hnf.1, hnf.2));
+    }
+    println!(); // newline to end \r shenanigans at start of loop
+
+    for hnf in &hnfs {
+        debug!("HN-F {:?}: {} lines", hnf, lines_per_hnf.get(hnf).unwrap_or(&0));
+    }
+    info!("Attributed {} lines to {} of {} HN-Fs", lines.len() - 1, lines_per_hnf.len(), hnfs.len());
+    writer.write_lines(lines, "slc_hash.csv", Some(cmn_folder(cmn_idx).as_str()))
+}
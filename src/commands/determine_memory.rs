@@ -0,0 +1,63 @@
+use log::{debug, info, warn};
+
+use crate::args::DetermineTopologyArgs;
+use crate::error::Result;
+use crate::perf::PerfBackend;
+use crate::topology::{NumaLayout, NumaNode};
+use crate::utils::{benchmark_command, cmn_folder, get_cmn_cpus, get_event_string, get_event_type_id, get_numa_nodes};
+use crate::writer::Writer;
+
+/// Requests sent from the HN-Fs to the memory controllers on a cache miss
+pub static HNF_MC_EVENT: &str = "hnf_mc_reqs";
+/// Requests sent via p0/p1 to the attached device, which for memory controllers (SN-F) originate from the HN-Fs
+pub static PORT_REQ_EVENTS: [&str; 2] = ["mxp_p0_req_txflit_valid", "mxp_p1_req_txflit_valid"];
+
+/// Determine which HN-Fs and memory controllers serve the memory of every NUMA node of the CMN
+///  The benchmark streams over a buffer on the first CPU of each NUMA node, so the buffer is allocated on that node.
+///  Meanwhile, the HN-F memory controller requests of every HN-F port and the requests sent via p0/p1 of every XP are
+///   recorded; the SN-Fs show up as ports receiving requests without any node observable by the counters.
+///  Depending on the NUMA configuration (monolithic, hemisphere, quadrant), the addresses of a node are hashed to all
+///   HN-Fs or only to those of its part of the mesh. The mapping itself is done by analyze-topology.
+pub fn determine(cmn_idx: u8, nodeid_length: u8, mesh_size: (u16, u16), args: &DetermineTopologyArgs,
+                 perf: &dyn PerfBackend, writer: &Writer) -> Result<()> {
+    info!("Determining Memory Controllers of CMN {cmn_idx}");
+
+    let nodes: Vec<NumaNode> = get_numa_nodes(&get_cmn_cpus(cmn_idx)?)?.into_iter()
+        .map(|(node, cpus)| NumaNode { node, cpus })
+        .collect();
+    if nodes.len() != args.numa_config.num_nodes() {
+        warn!("CMN {cmn_idx} has {} NUMA nodes, but {} mode implies {}", nodes.len(), args.numa_config,
+              args.numa_config.num_nodes());
+    }
+    writer.write_json(&NumaLayout { numa_config: args.numa_config, nodes: nodes.clone() }, "numa.json",
+                      Some(cmn_folder(cmn_idx).as_str()))?;
+
+    let hnf_event = get_event_type_id(cmn_idx, HNF_MC_EVENT)?;
+    let port_events = PORT_REQ_EVENTS.iter().map(|e| get_event_type_id(cmn_idx, e)).collect::<Result<Vec<_>>>()?;
+    let mut events = Vec::new();
+    for i in 0..mesh_size.0 {
+        for j in 0..mesh_size.1 {
+            for port in 0..=1 {
+                events.push(get_event_string(cmn_idx, i, j, port, nodeid_length, hnf_event.as_str()));
+            }
+            for port_event in &port_events {
+                events.push(get_event_string(cmn_idx, i, j, 0, nodeid_length, port_event.as_str()));
+            }
+        }
+    }
+
+    let folder = format!("{}/memory", cmn_folder(cmn_idx));
+    for node in nodes {
+        debug!("Streaming over {} MiB on CPU {} of NUMA node {}", args.memory_size, node.cpus[0], node.node);
+        let mut benchmark = benchmark_command(args)?;
+        benchmark.arg("--memory-size")
+            .arg(args.memory_size.to_string())
+            .arg("--cores")
+            .arg(node.cpus[0].to_string());
+        let (counts, _) = perf.stat(&events, benchmark, None)?;
+
+        let parsed_output = counts.to_events(nodeid_length)?;
+        writer.write_events(&parsed_output, format!("numa_{}", node.node).as_str(), Some(folder.as_str()))?;
+    }
+    Ok(())
+}
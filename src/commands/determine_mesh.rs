@@ -1,17 +1,79 @@
+use std::collections::BTreeSet;
+use std::fs;
 use std::process::Command;
 use log::{info,debug};
 
+use crate::debugfs::map_path;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::perf::PerfBackend;
+use crate::topology::MeshInfo;
 use crate::writer::Writer;
-use crate::utils::{get_event_string, get_event_type_id, perf_to_event_vec};
+use crate::utils::{cmn_folder, get_event_string, get_event_type_id};
+
+/// Node ID encodings supported by the CMN (refer to ARM CMN TRM), each covering meshes up to 4x4, 8x8, and 16x16
+static NODEID_LENGTHS: [u8; 3] = [7, 9, 11];
 
 /// Determine mesh size of CMN
 ///  Use one dummy MXP perf event (mxp_n_dat_txflit_valid) and try to measure it for all possible mesh nodes given the nodeid_length
 ///  Those nodes actually addressable will return <not counted> or some value, non-addressable (i.e. non-existing) nodes will return <not supported>
-pub fn determine(nodeid_length: u8, writer: &Writer) -> (u16,u16) {
-    info!("Determining Mesh Size");
+///  If no nodeid_length is given, it is detected as well (see `detect`)
+///  The mesh map of the arm-cmn driver is stored alongside if debugfs is accessible
+pub fn determine(cmn_idx: u8, nodeid_length: Option<u8>, perf: &dyn PerfBackend, writer: &Writer)
+                 -> Result<MeshInfo> {
+    info!("Determining Mesh Size of CMN {cmn_idx}");
+
+    let (mesh, parsed_output) = detect(cmn_idx, nodeid_length, perf)?;
+    writer.write_events(&parsed_output, "mxp", Some(cmn_folder(cmn_idx).as_str()))?;
+    writer.write_json(&mesh, "mesh.json", Some(cmn_folder(cmn_idx).as_str()))?;
+
+    match fs::read_to_string(map_path(cmn_idx)) {
+        Ok(map) => writer.write_lines(vec![map], "debugfs_map.txt", Some(cmn_folder(cmn_idx).as_str()))?,
+        Err(e) => debug!("Could not read debugfs map {:?} (needs root and mounted debugfs): {e}", map_path(cmn_idx))
+    }
+    Ok(mesh)
+}
+
+/// Detect node ID length and mesh size of CMN, returning the MXP probe events of the chosen node ID length
+///  Probing with a wrong node ID length may still hit existing XPs, as the bits of X and Y are shifted into each other.
+///  Only the correct length yields the full rectangular mesh, with the most XPs, and the least bits necessary to encode it.
+///  Fails if no XP could be addressed at all.
+pub fn detect(cmn_idx: u8, nodeid_length: Option<u8>, perf: &dyn PerfBackend) -> Result<(MeshInfo, Vec<Event>)> {
+    let candidates = match nodeid_length {
+        Some(nodeid_length) => vec![nodeid_length],
+        None => NODEID_LENGTHS.to_vec()
+    };
+
+    let mut probes = Vec::new();
+    for nodeid_length in candidates {
+        probes.push((nodeid_length, probe(cmn_idx, nodeid_length, perf)?));
+    }
+    let (nodeid_length, parsed_output) = select(probes)
+        .ok_or_else(|| Error::Config(format!("No node ID length to probe CMN {cmn_idx} with")))?;
+
+    let (mesh_x, mesh_y) = mesh_size(&supported_xps(&parsed_output));
+    if mesh_x == 0 || mesh_y == 0 {
+        return Err(Error::Perf(format!("No MXP of CMN {cmn_idx} could be addressed with node ID length \
+                                        {nodeid_length}, is the CMN PMU accessible?")))
+    }
+    info!("CMN {cmn_idx}: node ID length {nodeid_length}, mesh size {mesh_x}x{mesh_y}");
+    Ok((MeshInfo { cmn_idx, nodeid_length, mesh_x, mesh_y }, parsed_output))
+}
+
+/// Probe results (node ID length, MXP probe events) of the node ID length the mesh uses, see `detect`
+fn select(probes: Vec<(u8, Vec<Event>)>) -> Option<(u8, Vec<Event>)> {
+    probes.into_iter()
+        .inspect(|(nodeid_length, events)| debug!("Node ID length {nodeid_length}: {} XPs", supported_xps(events).len()))
+        .max_by_key(|(_, events)| {
+            let xps = supported_xps(events);
+            let (x, y) = mesh_size(&xps);
+            (xps.len() == (x * y) as usize, xps.len(), std::cmp::Reverse(x.max(y)))
+        })
+}
 
+fn probe(cmn_idx: u8, nodeid_length: u8, perf: &dyn PerfBackend) -> Result<Vec<Event>> {
     debug!("Getting MXP type and one event ID");
-    let mxp_event_type = get_event_type_id("mxp_n_dat_txflit_valid");
+    let mxp_event_type = get_event_type_id(cmn_idx, "mxp_n_dat_txflit_valid")?;
 
     let mesh_size: u16 = match nodeid_length {
         7  => 4,
@@ -23,25 +85,89 @@ pub fn determine(nodeid_length: u8, writer: &Writer) -> (u16,u16) {
 
     for i in 0..mesh_size {
         for j in 0..mesh_size {
-            events.push(String::from("-e"));
-            events.push(get_event_string(0, i, j, 1, nodeid_length, mxp_event_type.as_str()));
-            events.push(String::from("-e"));
-            events.push(get_event_string(0, i, j, 0, nodeid_length, mxp_event_type.as_str()));
+            events.push(get_event_string(cmn_idx, i, j, 1, nodeid_length, mxp_event_type.as_str()));
+            events.push(get_event_string(cmn_idx, i, j, 0, nodeid_length, mxp_event_type.as_str()));
         }
     }
 
-    let output = String::from_utf8(Command::new("perf")
-        .arg("stat")
-        .arg("--field-separator")
-        .arg(";")
-        .args(events)
-        .arg("sleep")
-        .arg(".01")
-        .output().unwrap().stderr).unwrap();
+    let mut sleep = Command::new("sleep");
+    sleep.arg(".01");
+    let (counts, _) = perf.stat(&events, sleep, None)?;
+    counts.to_events(nodeid_length)
+}
 
-    let parsed_output = perf_to_event_vec(output.as_str(), nodeid_length);
-    writer.write_events(&parsed_output, "mxp", None);
+/// (x, y) of all XPs which did not report <not supported>
+fn supported_xps(events: &[Event]) -> BTreeSet<(u16, u16)> {
+    events.iter()
+        .filter(|e| e.counts >= 0)
+        .map(|e| (e.node_id.x, e.node_id.y))
+        .collect()
+}
 
-    (parsed_output.iter().max_by_key(|event| event.node_id.x).unwrap().node_id.x + 1,
-     parsed_output.iter().max_by_key(|event| event.node_id.y).unwrap().node_id.y + 1)
-}
\ No newline at end of file
+fn mesh_size(xps: &BTreeSet<(u16, u16)>) -> (u16, u16) {
+    (xps.iter().map(|(x, _)| x + 1).max().unwrap_or(0),
+     xps.iter().map(|(_, y)| y + 1).max().unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::NodeID;
+
+    /// Probe results with the given node ID length, of a mesh with the given XPs and actual node ID length
+    ///  Like the driver, a probed node ID is supported if it addresses an existing XP with the actual node ID length.
+    fn probe(xps: &BTreeSet<(u16, u16)>, actual: u8, nodeid_length: u8) -> (u8, Vec<Event>) {
+        let size: u16 = 1 << ((nodeid_length - 3) / 2);
+        let nodeids = (0..size).flat_map(|x| (0..size).flat_map(move |y| (0..=1)
+            .map(move |port| NodeID { x, y, port, nodeid_length }.to_nodeid())));
+        (nodeid_length, nodeids.map(|nodeid| {
+            let node = NodeID::from_nodeid(nodeid, actual);
+            let exists = nodeid < 1 << actual && xps.contains(&(node.x, node.y));
+            Event {
+                cmn_idx: 0, event_type: 0x6, event_id: 0x1, node_id: NodeID::from_nodeid(nodeid, nodeid_length),
+                counts: if exists { 0 } else { -1 }, time_enabled: 0, time_running: 0, pass: 0
+            }
+        }).collect())
+    }
+
+    fn detected(xps: &BTreeSet<(u16, u16)>, actual: u8) -> (u8, (u16, u16), usize) {
+        let (nodeid_length, events) = select(NODEID_LENGTHS.iter().map(|l| probe(xps, actual, *l)).collect()).unwrap();
+        let supported = supported_xps(&events);
+        (nodeid_length, mesh_size(&supported), supported.len())
+    }
+
+    fn mesh(x: u16, y: u16) -> BTreeSet<(u16, u16)> {
+        (0..x).flat_map(|x| (0..y).map(move |y| (x, y))).collect()
+    }
+
+    #[test]
+    fn altra_mesh() {
+        // the 7- and 11-bit probes hit some XPs of the 8x6 mesh as well, just not as full rectangle
+        let xps = mesh(8, 6);
+        for nodeid_length in [7, 11] {
+            let (_, events) = probe(&xps, 9, nodeid_length);
+            assert!(!supported_xps(&events).is_empty());
+        }
+        assert_eq!(detected(&xps, 9), (9, (8, 6), 48));
+    }
+
+    #[test]
+    fn small_meshes() {
+        assert_eq!(detected(&mesh(3, 2), 7), (7, (3, 2), 6));
+        assert_eq!(detected(&mesh(4, 4), 7), (7, (4, 4), 16));
+        assert_eq!(detected(&mesh(12, 10), 11), (11, (12, 10), 120));
+    }
+
+    #[test]
+    fn mesh_with_hole() {
+        // no encoding yields a full rectangle, the wrong ones spread the XPs found over a larger mesh
+        let mut xps = mesh(3, 3);
+        xps.remove(&(1, 1));
+        assert_eq!(detected(&xps, 7), (7, (3, 3), 8));
+    }
+
+    #[test]
+    fn nothing_to_select() {
+        assert!(select(Vec::new()).is_none());
+    }
+}
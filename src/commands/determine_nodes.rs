@@ -1,40 +1,66 @@
 use std::process::Command;
 use log::{info,debug};
 
-use crate::utils::{get_event_string, get_event_type_id, perf_to_event_vec};
+use crate::error::Result;
+use crate::event::Event;
+use crate::perf::PerfBackend;
+use crate::topology::MeshInfo;
+use crate::utils::{cmn_folder, find_event, get_event_string, get_event_type_id};
 use crate::writer::Writer;
 
-/// Determine placement of HNF, HNI, and RNID nodes
+/// Event name prefixes of the CCIX gateway (CXG) nodes: request agent (local requests to the remote socket),
+///  home agent (remote requests to the local socket), and the link agent of CMN-700 gateways (CCLA)
+pub static GATEWAY_EVENT_PREFIXES: [&str; 3] = ["cxra_", "cxha_", "ccla_"];
+
+/// Determine placement of HNF, HNI, RNID, and CXG nodes
 ///  The CMN perf integration exposes counters for HNF, HNI, and RNI/RND nodes. Use same approach as with MXP node detection
-pub fn determine(nodeid_length: u8, mesh_size: (u16, u16), writer: &Writer){
-    info!("Determining Node Placement");
+///  CXG nodes only exist on multi-socket systems, newer kernels hide their events otherwise, so they are probed if available
+///  Returns the probe events, as written to `nodes.csv`
+pub fn determine(cmn_idx: u8, nodeid_length: u8, mesh_size: (u16, u16), perf: &dyn PerfBackend,
+                 writer: &Writer) -> Result<Vec<Event>> {
+    info!("Determining Node Placement of CMN {cmn_idx}");
 
     let mut out_events = Vec::new();
     debug!("Getting placements of HNF, HNI, and RNID nodes");
-    for event_type in [ get_event_type_id("hnf_seq_full"),
-                              get_event_type_id("hni_arready_no_arvalid"),
-                              get_event_type_id("rnid_rdb_hybrid") ] {
-        let mut events = Vec::new();
-        for i in 0..mesh_size.0 {
-            for j in 0..mesh_size.1 {
-                events.push(String::from("-e"));
-                events.push(get_event_string(0, i, j, 1, nodeid_length, event_type.as_str()));
-                events.push(String::from("-e"));
-                events.push(get_event_string(0, i, j, 0, nodeid_length, event_type.as_str()));
+    for event_name in ["hnf_seq_full", "hni_arready_no_arvalid", "rnid_rdb_hybrid"] {
+        let event_type = get_event_type_id(cmn_idx, event_name)?;
+        out_events.append(&mut probe(cmn_idx, nodeid_length, mesh_size, event_type.as_str(), perf)?);
+    }
+    let mesh = MeshInfo { cmn_idx, nodeid_length, mesh_x: mesh_size.0, mesh_y: mesh_size.1 };
+    out_events.append(&mut probe_gateways(&mesh, perf)?);
+    writer.write_events(&out_events, "nodes", Some(cmn_folder(cmn_idx).as_str()))?;
+    Ok(out_events)
+}
+
+/// Probe CXRA, CXHA and CCLA nodes on every port, i.e. the CCIX gateways to the other socket(s)
+pub fn probe_gateways(mesh: &MeshInfo, perf: &dyn PerfBackend) -> Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for prefix in GATEWAY_EVENT_PREFIXES {
+        match find_event(mesh.cmn_idx, prefix) {
+            Some(name) => {
+                debug!("Getting placements of gateway nodes via {name}");
+                events.append(&mut probe(mesh.cmn_idx, mesh.nodeid_length, (mesh.mesh_x, mesh.mesh_y),
+                                         get_event_type_id(mesh.cmn_idx, name.as_str())?.as_str(), perf)?);
             }
+            None => debug!("CMN {} exposes no {prefix}* events, skipping gateway nodes", mesh.cmn_idx)
         }
+    }
+    Ok(events)
+}
 
-        let output = String::from_utf8(Command::new("perf")
-            .arg("stat")
-            .arg("--field-separator")
-            .arg(";")
-            .args(events)
-            .arg("sleep")
-            .arg(".01")
-            .output().unwrap().stderr).unwrap();
-
-        let mut parsed_output = perf_to_event_vec(output.as_str(), nodeid_length);
-        out_events.append(&mut parsed_output);
+/// Measure the given event on both ports of every XP, non-existing nodes report <not supported>
+fn probe(cmn_idx: u8, nodeid_length: u8, mesh_size: (u16, u16), event_type: &str, perf: &dyn PerfBackend)
+         -> Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for i in 0..mesh_size.0 {
+        for j in 0..mesh_size.1 {
+            events.push(get_event_string(cmn_idx, i, j, 1, nodeid_length, event_type));
+            events.push(get_event_string(cmn_idx, i, j, 0, nodeid_length, event_type));
+        }
     }
-    writer.write_events(&out_events, "nodes", None);
-}
\ No newline at end of file
+
+    let mut sleep = Command::new("sleep");
+    sleep.arg(".01");
+    let (counts, _) = perf.stat(&events, sleep, None)?;
+    counts.to_events(nodeid_length)
+}
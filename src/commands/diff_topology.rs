@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use log::{info, warn};
+
+use crate::args::DiffTopologyArgs;
+use crate::commands::analyze_topology::{build_topology, cmn_run_dirs};
+use crate::error::{Error, Result};
+use crate::topology::{NumaLayout, Topology};
+use crate::utils::read_json;
+use crate::writer::Writer;
+
+type PortLocation = (u16, u16, u16);
+
+/// Topology of one CMN, along with its NUMA nodes if recorded
+struct CmnLayout {
+    topology: Topology,
+    numa: Option<NumaLayout>,
+}
+
+/// Compare the topologies of two runs or hosts and write every difference to `diff.csv`
+///  Both sides may be a `topology.json`, an analyze-topology output directory, or a determine-topology run directory
+///   (analyzed on the fly). Reported are differing meshes, missing XPs, node type changes, moved DSUs, missing CPUs
+///   (e.g. fused-off cores), and differing NUMA configurations.
+///  Returns whether any difference was found
+pub fn diff_topology(args: &DiffTopologyArgs, nodeid_length: Option<u8>, cores_per_dsu: Option<u16>,
+                     writer: &Writer) -> Result<bool> {
+    let a = load(Path::new(&args.a), nodeid_length, cores_per_dsu)?;
+    let b = load(Path::new(&args.b), nodeid_length, cores_per_dsu)?;
+
+    let mut lines = vec![String::from("cmn;kind;item;a;b")];
+    for cmn_idx in a.keys().chain(b.keys()).collect::<BTreeSet<_>>() {
+        let differences = match (a.get(cmn_idx), b.get(cmn_idx)) {
+            (Some(a), Some(b)) => diff(a, b),
+            (a, b) => vec![(String::from("cmn"), format!("cmn_{cmn_idx}"), presence(a.is_some()), presence(b.is_some()))]
+        };
+        for (kind, item, in_a, in_b) in differences {
+            warn!("CMN {cmn_idx}: {kind} {item} differs: {in_a} vs. {in_b}");
+            lines.push(format!("{cmn_idx};{kind};{item};{in_a};{in_b}"));
+        }
+    }
+
+    let mismatch = lines.len() > 1;
+    match mismatch {
+        true => warn!("Topologies differ in {} places", lines.len() - 1),
+        false => info!("Topologies are identical")
+    }
+    writer.write_lines(lines, "diff.csv", None)?;
+    Ok(mismatch)
+}
+
+fn presence(present: bool) -> String {
+    String::from(if present { "present" } else { "missing" })
+}
+
+fn format_location(location: Option<PortLocation>) -> String {
+    match location {
+        Some((x, y, port)) => format!("{x},{y},{port}"),
+        None => String::from("missing")
+    }
+}
+
+/// Topologies by CMN index of a topology file, analyze-topology output, or determine-topology run
+fn load(path: &Path, nodeid_length: Option<u8>, cores_per_dsu: Option<u16>) -> Result<BTreeMap<u8, CmnLayout>> {
+    if path.is_file() {
+        let content = fs::read_to_string(path).map_err(Error::io(path))?;
+        let topology: Topology = serde_json::from_str(content.as_str())
+            .map_err(|e| Error::parse(path.display().to_string(), e.to_string()))?;
+        return Ok(BTreeMap::from([(topology.cmn_idx, CmnLayout { topology, numa: None })]))
+    }
+    let mut layouts = BTreeMap::new();
+    for (cmn_idx, cmn_dir) in cmn_run_dirs(path)? {
+        let topology = match read_json(cmn_dir.join("topology.json").as_path())? {
+            Some(topology) => topology,
+            None => build_topology(path, cmn_idx, nodeid_length, cores_per_dsu)?
+        };
+        layouts.insert(cmn_idx, CmnLayout { topology, numa: read_json(cmn_dir.join("numa.json").as_path())? });
+    }
+    Ok(layouts)
+}
+
+/// Differences between two layouts of the same CMN as (kind, item, a, b)
+fn diff(a: &CmnLayout, b: &CmnLayout) -> Vec<(String, String, String, String)> {
+    let mut differences = Vec::new();
+    let mut push = |kind: &str, item: String, in_a: String, in_b: String| {
+        differences.push((String::from(kind), item, in_a, in_b));
+    };
+    let (ta, tb) = (&a.topology, &b.topology);
+
+    if (ta.mesh_x, ta.mesh_y) != (tb.mesh_x, tb.mesh_y) {
+        push("mesh", String::from("size"), format!("{}x{}", ta.mesh_x, ta.mesh_y), format!("{}x{}", tb.mesh_x, tb.mesh_y));
+    }
+    if let (Some(na), Some(nb)) = (&a.numa, &b.numa) {
+        if na.numa_config != nb.numa_config || na.nodes.len() != nb.nodes.len() {
+            push("numa", String::from("config"), format!("{} ({} nodes)", na.numa_config, na.nodes.len()),
+                 format!("{} ({} nodes)", nb.numa_config, nb.nodes.len()));
+        }
+    }
+
+    let xps_a: BTreeSet<(u16, u16)> = ta.xps.iter().map(|xp| (xp.x, xp.y)).collect();
+    let xps_b: BTreeSet<(u16, u16)> = tb.xps.iter().map(|xp| (xp.x, xp.y)).collect();
+    for (x, y) in xps_a.symmetric_difference(&xps_b) {
+        push("xp", format!("{x},{y}"), presence(xps_a.contains(&(*x, *y))), presence(xps_b.contains(&(*x, *y))));
+    }
+
+    let node_types = |t: &Topology| t.xps.iter()
+        .flat_map(|xp| xp.ports.iter().map(move |p| ((xp.x, xp.y, p.port), p.node_type)))
+        .collect::<BTreeMap<PortLocation, _>>();
+    let (types_a, types_b) = (node_types(ta), node_types(tb));
+    for (location, type_a) in &types_a {
+        match types_b.get(location) {
+            Some(type_b) if type_a != type_b => push("node_type", format_location(Some(*location)),
+                                                     type_a.to_string(), type_b.to_string()),
+            _ => {}
+        }
+    }
+
+    // CPUs placed differently, grouped by their locations in a and b, so a moved DSU shows up as one entry
+    let cpus = |t: &Topology| t.xps.iter()
+        .flat_map(|xp| xp.ports.iter().flat_map(move |p| p.cpus.iter().map(move |cpu| (*cpu, (xp.x, xp.y, p.port)))))
+        .collect::<BTreeMap<u16, PortLocation>>();
+    let (cpus_a, cpus_b) = (cpus(ta), cpus(tb));
+    let mut moved: BTreeMap<(Option<PortLocation>, Option<PortLocation>), Vec<u16>> = BTreeMap::new();
+    for cpu in cpus_a.keys().chain(cpus_b.keys()).collect::<BTreeSet<_>>() {
+        let (location_a, location_b) = (cpus_a.get(cpu).copied(), cpus_b.get(cpu).copied());
+        if location_a != location_b {
+            moved.entry((location_a, location_b)).or_default().push(*cpu);
+        }
+    }
+    for ((location_a, location_b), cpus) in moved {
+        let kind = if location_a.is_some() && location_b.is_some() { "dsu_moved" } else { "cpus_missing" };
+        push(kind, format!("cpus {}", cpus.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(",")),
+             format_location(location_a), format_location(location_b));
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::NUMAConfig;
+    use crate::topology::{NodeType, NumaNode, Port, Xp};
+
+    /// 2x1 mesh with DSUs at (0,0,0) and (1,0,1), and an HN-F at (1,0,0), split into two NUMA nodes
+    fn layout() -> CmnLayout {
+        let port = |port, node_type, cpus: &[u16]| Port {
+            port, node_type, cpus: cpus.to_vec(), conflicting_types: Vec::new()
+        };
+        let topology = Topology { cmn_idx: 0, nodeid_length: 7, mesh_x: 2, mesh_y: 1, xps: vec![
+            Xp { x: 0, y: 0, ports: vec![port(0, NodeType::RnF, &[0, 1]), port(1, NodeType::XpOnly, &[])] },
+            Xp { x: 1, y: 0, ports: vec![port(0, NodeType::HnF, &[]), port(1, NodeType::RnF, &[2, 3])] },
+        ]};
+        let numa = NumaLayout { numa_config: NUMAConfig::Hemisphere, nodes: vec![
+            NumaNode { node: 0, cpus: vec![0, 1] },
+            NumaNode { node: 1, cpus: vec![2, 3] },
+        ]};
+        CmnLayout { topology, numa: Some(numa) }
+    }
+
+    fn row(kind: &str, item: &str, a: &str, b: &str) -> (String, String, String, String) {
+        (String::from(kind), String::from(item), String::from(a), String::from(b))
+    }
+
+    #[test]
+    fn identical() {
+        assert!(diff(&layout(), &layout()).is_empty());
+    }
+
+    #[test]
+    fn moved_dsu() {
+        let mut b = layout();
+        b.topology.xps[0].ports[0].cpus.clear();
+        b.topology.xps[0].ports[1].cpus = vec![0, 1];
+        assert_eq!(diff(&layout(), &b), vec![row("dsu_moved", "cpus 0,1", "0,0,0", "0,0,1")]);
+    }
+
+    #[test]
+    fn missing_xp() {
+        let mut b = layout();
+        b.topology.xps.pop();
+        assert_eq!(diff(&layout(), &b), vec![row("xp", "1,0", "present", "missing"),
+                                             row("cpus_missing", "cpus 2,3", "1,0,1", "missing")]);
+    }
+
+    #[test]
+    fn node_type_change() {
+        let mut b = layout();
+        b.topology.xps[1].ports[0].node_type = NodeType::HnI;
+        assert_eq!(diff(&layout(), &b), vec![row("node_type", "1,0,0", "HN-F", "HN-I")]);
+    }
+
+    #[test]
+    fn missing_cpus() {
+        // a fused-off core
+        let mut b = layout();
+        b.topology.xps[1].ports[1].cpus.pop();
+        assert_eq!(diff(&layout(), &b), vec![row("cpus_missing", "cpus 3", "1,0,1", "missing")]);
+        assert_eq!(diff(&b, &layout()), vec![row("cpus_missing", "cpus 3", "missing", "1,0,1")]);
+    }
+
+    #[test]
+    fn mesh_size() {
+        let mut b = layout();
+        b.topology.mesh_y = 2;
+        assert_eq!(diff(&layout(), &b), vec![row("mesh", "size", "2x1", "2x2")]);
+    }
+
+    #[test]
+    fn numa() {
+        let mut b = layout();
+        b.numa = Some(NumaLayout { numa_config: NUMAConfig::Monolithic, nodes: vec![
+            NumaNode { node: 0, cpus: vec![0, 1, 2, 3] },
+        ]});
+        assert_eq!(diff(&layout(), &b), vec![row("numa", "config", "hemisphere (2 nodes)", "monolithic (1 nodes)")]);
+        // without a recorded NUMA layout on one side, it is not compared
+        b.numa = None;
+        assert!(diff(&layout(), &b).is_empty());
+    }
+}
@@ -1,47 +1,49 @@
-use std::fs;
 use std::process::{Command, Stdio};
+use std::time::Duration;
 use log::{info, debug};
 
 use crate::args::LaunchArgs;
-use crate::utils::{events_to_perf_events, perf_to_event_vec};
+use crate::commands::cross_socket::{write_report, Gateways};
+use crate::perf::{Counts, PerfBackend};
+use crate::error::{Error, Result};
+use crate::topology::MeshInfo;
+use crate::utils::{cmn_folder, events_to_perf_events, get_online_cpus};
 use crate::writer::Writer;
 
 /// Launch application while observing CMN with given perf events
-pub fn launch(args: &LaunchArgs, mesh_size: (u16,u16),  nodeid_length: u8, events: Option<Vec<String>>, writer: &Writer) {
+///  If gateways are given (cross-socket preset), their egress and ingress flits are reported as well
+///  With an interval, the counts of every interval are written to cmn_N/time_series.csv as well
+pub fn launch(args: &LaunchArgs, meshes: &[MeshInfo], events: Option<Vec<String>>, gateways: Option<&Gateways>,
+              interval: Option<Duration>, perf: &dyn PerfBackend, writer: &Writer) -> Result<()> {
 
-    let num_procs = fs::read_to_string("/proc/cpuinfo").unwrap()
-        .split("\n")
-        .filter(|l| l.starts_with("processor"))
-        .map(|l| l.split(":").skip(1).next().unwrap().trim())
-        .last().unwrap()
-        .parse::<u16>().unwrap();
+    let num_procs = get_online_cpus()?.last().copied().unwrap_or(0);
 
     let mut perf_events = Vec::new();
     if let Some(_events) = events {
-        for i in 0..mesh_size.0 {
-            for j in 0..mesh_size.1 {
-                perf_events.append(&mut events_to_perf_events(_events.clone(), i, j, nodeid_length));
+        for mesh in meshes {
+            for i in 0..mesh.mesh_x {
+                for j in 0..mesh.mesh_y {
+                    perf_events.append(&mut events_to_perf_events(mesh.cmn_idx, _events.clone(), i, j,
+                                                                  mesh.nodeid_length)?);
+                }
             }
         }
     }
 
     let mut cmd = Command::new("taskset");
     cmd.arg("--cpu-list")
-        .arg(args.core_map.clone().unwrap_or(String::from(format!("0-{num_procs}"))));
+        .arg(args.core_map.clone().unwrap_or(format!("0-{num_procs}")));
 
     if let Some(shell) = args.shell.clone() { cmd.arg(shell).arg("-c"); }
 
-    if perf_events.len() > 0 {
-        cmd.arg("perf").arg("stat").arg("--field-separator").arg(";").args(perf_events);
-    }
-
     cmd.arg(args.binary.clone());
     if let Some(args) = args.args.clone() { cmd.args(args); }
 
     if let Some(env) = args.env.clone() {
         for entry in env {
-            let mut parts = entry.split("=");
-            cmd.env(parts.next().unwrap(), parts.next().unwrap());
+            let (key, value) = entry.split_once("=")
+                .ok_or_else(|| Error::Config(format!("Invalid environment variable `{entry}`, expected NAME=value")))?;
+            cmd.env(key, value);
         }
     }
 
@@ -54,10 +56,33 @@ pub fn launch(args: &LaunchArgs, mesh_size: (u16,u16),  nodeid_length: u8, event
 
     info!("Launching application {}", args.binary);
     debug!("Command: `{:?}`", cmd);
-    let cmd_output = cmd.spawn().unwrap().wait_with_output().unwrap();
-    let output_stderr =  String::from_utf8(cmd_output.stderr).unwrap();
-    let parsed_output = perf_to_event_vec(output_stderr.as_str(), nodeid_length);
-    writer.write_events(&parsed_output, "measurements", None);
-    writer.write_lines(vec![String::from_utf8(cmd_output.stdout).unwrap()], "stdout.txt");
-    writer.write_lines(vec![output_stderr], "stderr.txt");
-}
\ No newline at end of file
+    let run = |mut cmd: Command| cmd.stdin(Stdio::inherit()).output().map_err(Error::command(&args.binary));
+    let (counts, cmd_output) = match perf_events.is_empty() {
+        true => (Default::default(), run(cmd)?),
+        false => perf.stat(&perf_events, cmd, interval)?,
+    };
+    let output_stderr = String::from_utf8_lossy(&cmd_output.stderr).to_string();
+    for mesh in meshes {
+        // node IDs can only be decoded with the node ID length of the respective CMN
+        let parsed_output: Vec<_> = counts.to_events(mesh.nodeid_length)?.into_iter()
+            .filter(|e| e.cmn_idx == mesh.cmn_idx)
+            .collect();
+        writer.write_events(&parsed_output, "measurements", Some(cmn_folder(mesh.cmn_idx).as_str()))?;
+        if interval.is_some() {
+            write_time_series(&counts, mesh, writer)?;
+        }
+        if let Some(gateways) = gateways.and_then(|g| g.get(&mesh.cmn_idx)) {
+            write_report(mesh, gateways, &parsed_output, writer)?;
+        }
+    }
+    writer.write_lines(vec![String::from_utf8_lossy(&cmd_output.stdout).to_string()], "stdout.txt", None)?;
+    writer.write_lines(vec![output_stderr], "stderr.txt", None)
+}
+
+/// Write the counts of every interval of the given CMN to cmn_N/time_series.csv
+pub fn write_time_series(counts: &Counts, mesh: &MeshInfo, writer: &Writer) -> Result<()> {
+    let intervals: Vec<_> = counts.interval_events(mesh.nodeid_length)?.into_iter()
+        .map(|(time, events)| (time, events.into_iter().filter(|e| e.cmn_idx == mesh.cmn_idx).collect()))
+        .collect();
+    writer.write_time_series(&intervals, "time_series", Some(cmn_folder(mesh.cmn_idx).as_str()))
+}
@@ -3,30 +3,38 @@ use std::fs;
 use std::process::{Child, Command, Stdio};
 use std::thread::sleep;
 use std::time::Duration;
-use log::info;
+use log::{debug, info};
 
 use crate::args::{LaunchMultiArgs, LaunchMultiConfig};
-use crate::utils::{events_to_perf_events, perf_to_event_vec};
+use crate::commands::cross_socket::{write_report, Gateways};
+use crate::commands::launch::write_time_series;
+use crate::perf::PerfBackend;
+use crate::error::{Error, Result};
+use crate::topology::MeshInfo;
+use crate::utils::{cmn_folder, events_to_perf_events, get_online_cpus};
 use crate::writer::Writer;
 
 /// Launch multiple applications while observing CMN with given perf events
-pub fn launch_multi(args: &LaunchMultiArgs, mesh_size: (u16,u16),  nodeid_length: u8, events: Option<Vec<String>>,
-                    writer: &mut Writer) {
-    let num_procs = fs::read_to_string("/proc/cpuinfo").unwrap()
-        .split("\n")
-        .filter(|l| l.starts_with("processor"))
-        .map(|l| l.split(":").skip(1).next().unwrap().trim())
-        .last().unwrap()
-        .parse::<u16>().unwrap();
-
-    let config: LaunchMultiConfig = serde_json::from_str(fs::read_to_string(args.config.clone()).unwrap().as_str()).unwrap();
+///  If gateways are given (cross-socket preset), their egress and ingress flits are reported as well
+///  With an interval, the counts of every interval are written to cmn_N/time_series.csv as well
+pub fn launch_multi(args: &LaunchMultiArgs, meshes: &[MeshInfo], events: Option<Vec<String>>,
+                    gateways: Option<&Gateways>, interval: Option<Duration>, perf: &dyn PerfBackend,
+                    writer: &mut Writer) -> Result<()> {
+    let num_procs = get_online_cpus()?.last().copied().unwrap_or(0);
+
+    let config = fs::read_to_string(&args.config).map_err(Error::io(&args.config))?;
+    let config: LaunchMultiConfig = serde_json::from_str(&config)
+        .map_err(|e| Error::Config(format!("Invalid config {}: {e}", args.config)))?;
     writer.additional_args = serde_json::to_string(&config).unwrap();
 
     let mut perf_events = Vec::new();
     if let Some(_events) = events {
-        for i in 0..mesh_size.0 {
-            for j in 0..mesh_size.1 {
-                perf_events.append(&mut events_to_perf_events(_events.clone(), i, j, nodeid_length));
+        for mesh in meshes {
+            for i in 0..mesh.mesh_x {
+                for j in 0..mesh.mesh_y {
+                    perf_events.append(&mut events_to_perf_events(mesh.cmn_idx, _events.clone(), i, j,
+                                                                  mesh.nodeid_length)?);
+                }
             }
         }
     }
@@ -36,7 +44,7 @@ pub fn launch_multi(args: &LaunchMultiArgs, mesh_size: (u16,u16),  nodeid_length
         let mut cmd = Command::new("taskset");
 
         cmd.arg("--cpu-list")
-            .arg(exec.core_map.clone().unwrap_or(String::from(format!("0-{num_procs}"))));
+            .arg(exec.core_map.clone().unwrap_or(format!("0-{num_procs}")));
 
         if let Some(shell) = exec.shell.clone() { cmd.arg(shell).arg("-c"); }
 
@@ -45,7 +53,9 @@ pub fn launch_multi(args: &LaunchMultiArgs, mesh_size: (u16,u16),  nodeid_length
 
         if let Some(env) = exec.env {
             for (k,v) in env {
-                cmd.env(k, v.as_str().unwrap());
+                let v = v.as_str()
+                    .ok_or_else(|| Error::Config(format!("Environment variable {k} of {} is not a string", exec.binary)))?;
+                cmd.env(k, v);
             }
         }
 
@@ -54,43 +64,54 @@ pub fn launch_multi(args: &LaunchMultiArgs, mesh_size: (u16,u16),  nodeid_length
         commands.push((exec.binary, cmd));
     }
 
-    let mut perf_cmd = Command::new("perf");
-    perf_cmd.arg("stat").arg("--field-separator").arg(";").args(perf_events);
-    perf_cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
-    let perf_proc = perf_cmd.spawn().unwrap();
+    let mut session = perf.start(&perf_events, interval)?;
 
 
     let mut id_name_map = HashMap::new();
     info!("Launching {} applications", commands.len());
-    let mut inflight: Vec<Child> = commands.iter_mut().map(|(binary,c)| {
-        let child = c.spawn().unwrap();
-        id_name_map.insert(child.id(), binary.split("/").last().unwrap());
-        child
-    }).collect();
+    let mut inflight: Vec<Child> = Vec::new();
+    for (binary, c) in commands.iter_mut() {
+        let child = c.spawn().map_err(Error::command(&*binary))?;
+        id_name_map.insert(child.id(), binary.split("/").last().unwrap_or(binary));
+        inflight.push(child);
+    }
 
-    while inflight.iter_mut().fold(false, |acc, c| acc || c.try_wait().unwrap().is_none() ) {
+    // an application whose state cannot be queried is treated as exited
+    while inflight.iter_mut().any(|c| matches!(c.try_wait(), Ok(None))) {
         sleep(Duration::from_secs(1));
+        if let Some(counts) = session.read() {
+            debug!("{} events counted so far", counts.counts.iter().map(|c| c.counts.max(0)).sum::<i128>());
+        }
     }
 
     for child in inflight {
-        let id = child.id().clone();
-        let output = child.wait_with_output().unwrap();
-
-        writer.write_lines(vec![String::from_utf8(output.clone().stdout).unwrap()],
-                           format!("{}-{}.stdout", id, id_name_map.get(&id).unwrap()).as_str());
-        writer.write_lines(vec![String::from_utf8(output.clone().stderr).unwrap()],
-                           format!("{}-{}.stderr", id, id_name_map.get(&id).unwrap()).as_str());
+        let id = child.id();
+        let name = id_name_map[&id];
+        let output = child.wait_with_output().map_err(Error::command(name))?;
+
+        writer.write_lines(vec![String::from_utf8_lossy(&output.stdout).to_string()],
+                           format!("{}-{}.stdout", id, name).as_str(), None)?;
+        writer.write_lines(vec![String::from_utf8_lossy(&output.stderr).to_string()],
+                           format!("{}-{}.stderr", id, name).as_str(), None)?;
     }
 
-    // send ^C / SIGINT to perf_proc
-    let _ = Command::new("kill")
-        .args(["-s", "INT", &perf_proc.id().to_string()])
-        .spawn().unwrap().wait();
-
-    let cmd_output = perf_proc.wait_with_output().unwrap();
-    let output_stderr =  String::from_utf8(cmd_output.stderr).unwrap();
-    let parsed_output = perf_to_event_vec(output_stderr.as_str(), nodeid_length);
-    writer.write_events(&parsed_output, "measurements", None);
-    writer.write_lines(vec![String::from_utf8(cmd_output.stdout).unwrap()], "stdout.txt");
-    writer.write_lines(vec![output_stderr], "stderr.txt");
+    let counts = session.stop()?;
+    for mesh in meshes {
+        // node IDs can only be decoded with the node ID length of the respective CMN
+        let parsed_output: Vec<_> = counts.to_events(mesh.nodeid_length)?.into_iter()
+            .filter(|e| e.cmn_idx == mesh.cmn_idx)
+            .collect();
+        writer.write_events(&parsed_output, "measurements", Some(cmn_folder(mesh.cmn_idx).as_str()))?;
+        if interval.is_some() {
+            write_time_series(&counts, mesh, writer)?;
+        }
+        if let Some(gateways) = gateways.and_then(|g| g.get(&mesh.cmn_idx)) {
+            write_report(mesh, gateways, &parsed_output, writer)?;
+        }
+    }
+    // the report of perf stat, the native backend has none
+    if !counts.report.is_empty() {
+        writer.write_lines(vec![counts.report], "stderr.txt", None)?;
+    }
+    Ok(())
 }
\ No newline at end of file
@@ -1,6 +1,13 @@
 pub(crate) mod determine_cores;
 pub(crate) mod determine_nodes;
 pub(crate) mod determine_mesh;
+pub(crate) mod determine_memory;
+pub(crate) mod determine_hash;
+pub(crate) mod analyze_topology;
+pub(crate) mod route_check;
+pub(crate) mod diff_topology;
+pub(crate) mod validate;
+pub(crate) mod cross_socket;
 
 pub(crate) mod launch;
 pub(crate) mod launch_multi;
\ No newline at end of file
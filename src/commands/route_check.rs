@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::Path;
+use log::{info, warn};
+
+use crate::args::RouteCheckArgs;
+use crate::commands::analyze_topology::{build_topology, cmn_run_dirs, read_core_measurements};
+use crate::commands::determine_cores::LINK_EVENTS;
+use crate::error::Result;
+use crate::utils::{cmn_folder, get_event, read_events_map};
+use crate::writer::Writer;
+
+/// Direction of an XP's outgoing link, same order as `LINK_EVENTS`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    North,
+    East,
+    South,
+    West
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Direction::North => "N",
+            Direction::East  => "E",
+            Direction::South => "S",
+            Direction::West  => "W",
+        };
+        write!(f, "{name}")
+    }
+}
+
+type Link = (u16, u16, Direction);
+
+/// Links (XP and outgoing direction) a flit passes with dimension-ordered routing: first along X, then along Y
+///  X grows to the east, Y to the north
+pub fn xy_route(from: (u16, u16), to: (u16, u16)) -> Vec<Link> {
+    let (mut x, mut y) = from;
+    let mut links = Vec::new();
+    while x != to.0 {
+        if x < to.0 {
+            links.push((x, y, Direction::East));
+            x += 1;
+        } else {
+            links.push((x, y, Direction::West));
+            x -= 1;
+        }
+    }
+    while y != to.1 {
+        if y < to.1 {
+            links.push((x, y, Direction::North));
+            y += 1;
+        } else {
+            links.push((x, y, Direction::South));
+            y -= 1;
+        }
+    }
+    links
+}
+
+/// Compare the links which carried data flits during the core measurements with the expected XY routes
+///  The benchmark bounces a cache line between both cores, so data flits travel in both directions between their XPs.
+///  Only the direct routes between the cores are expected, which assumes direct cache transfer (DCT): the owner sends
+///   the line straight to the requester, the HN-F its address hashes to only sees requests, snoops and responses.
+///   Without DCT the data passes that HN-F, whose location is not known here, so links towards it show up as unexpected.
+///  A link is active if it carried at least `threshold` times the flits of the busiest device port (p0/p1),
+///   so links only carrying background traffic stay below it.
+///  Every expected link which stayed quiet and every unexpected active link is reported in `cmn_N/route_check.csv`,
+///   as they hint at a wrong core placement or a routing scheme other than XY.
+pub fn route_check(args: &RouteCheckArgs, nodeid_length: Option<u8>, cores_per_dsu: Option<u16>, writer: &Writer)
+                   -> Result<()> {
+    let run_dir = Path::new(&args.run_dir);
+    let events_map = read_events_map(run_dir.join("events.csv").as_path())?;
+    let link_events: Vec<(u8, u16)> = LINK_EVENTS.iter()
+        .map(|name| get_event(&events_map, name))
+        .collect::<Result<_>>()?;
+    let port_events: Vec<(u8, u16)> = ["mxp_p0_dat_txflit_valid", "mxp_p1_dat_txflit_valid"].iter()
+        .map(|name| get_event(&events_map, name))
+        .collect::<Result<_>>()?;
+    let directions = [Direction::North, Direction::East, Direction::South, Direction::West];
+
+    for (cmn_idx, cmn_dir) in cmn_run_dirs(run_dir)? {
+        info!("Checking routes of CMN {cmn_idx}");
+        let topology = build_topology(run_dir, cmn_idx, nodeid_length, cores_per_dsu)?;
+
+        let mut lines = vec![String::from("core_a;core_b;x;y;direction;mismatch;counts")];
+        let mut num_checked = 0;
+        let mut num_mismatched = 0;
+        for ((a, b), events) in read_core_measurements(cmn_dir.as_path(), topology.nodeid_length)? {
+            let mut link_counts: BTreeMap<Link, i128> = BTreeMap::new();
+            for e in events.iter().filter(|e| e.counts > 0) {
+                if let Some(i) = link_events.iter().position(|l| *l == (e.event_type, e.event_id)) {
+                    *link_counts.entry((e.node_id.x, e.node_id.y, directions[i])).or_default() += e.counts;
+                }
+            }
+            if link_counts.is_empty() {
+                warn!("No link data flits recorded for cores {a},{b}, was determine-topology run with --record-links?");
+                continue
+            }
+            let (Some(loc_a), Some(loc_b)) = (topology.cpu_location(a), topology.cpu_location(b)) else {
+                warn!("Cores {a},{b} have not been placed, skipping");
+                continue
+            };
+
+            let xp_a = (loc_a.0, loc_a.1);
+            let xp_b = (loc_b.0, loc_b.1);
+            let expected: BTreeSet<Link> = xy_route(xp_a, xp_b).into_iter().chain(xy_route(xp_b, xp_a)).collect();
+            // flits on the cores' device ports are the reference for how much traffic a link on the route carries
+            let max_counts = events.iter()
+                .filter(|e| port_events.contains(&(e.event_type, e.event_id)))
+                .map(|e| e.counts)
+                .max()
+                .unwrap_or(0);
+            let active: BTreeSet<Link> = link_counts.iter()
+                .filter(|(_, c)| **c as f64 >= max_counts as f64 * args.threshold)
+                .map(|(l, _)| *l)
+                .collect();
+
+            num_checked += 1;
+            let mut mismatches = Vec::new();
+            for link in expected.difference(&active) {
+                mismatches.push((*link, "missing"));
+            }
+            for link in active.difference(&expected) {
+                mismatches.push((*link, "unexpected"));
+            }
+            if !mismatches.is_empty() {
+                num_mismatched += 1;
+            }
+            for ((x, y, direction), mismatch) in mismatches {
+                lines.push(format!("{a};{b};{x};{y};{direction};{mismatch};{}",
+                                   link_counts.get(&(x, y, direction)).copied().unwrap_or(0)));
+            }
+        }
+        info!("CMN {cmn_idx}: {num_mismatched} of {num_checked} core pairs deviate from XY routing");
+        writer.write_lines(lines, "route_check.csv", Some(cmn_folder(cmn_idx).as_str()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Direction::*;
+
+    #[test]
+    fn same_xp() {
+        assert!(xy_route((2, 3), (2, 3)).is_empty());
+    }
+
+    #[test]
+    fn same_row() {
+        assert_eq!(xy_route((0, 1), (2, 1)), vec![(0, 1, East), (1, 1, East)]);
+        assert_eq!(xy_route((2, 1), (0, 1)), vec![(2, 1, West), (1, 1, West)]);
+    }
+
+    #[test]
+    fn same_column() {
+        assert_eq!(xy_route((1, 0), (1, 2)), vec![(1, 0, North), (1, 1, North)]);
+        assert_eq!(xy_route((1, 2), (1, 0)), vec![(1, 2, South), (1, 1, South)]);
+    }
+
+    #[test]
+    fn diagonal() {
+        // X first, so both directions take different links
+        assert_eq!(xy_route((0, 0), (1, 2)), vec![(0, 0, East), (1, 0, North), (1, 1, North)]);
+        assert_eq!(xy_route((1, 2), (0, 0)), vec![(1, 2, West), (0, 2, South), (0, 1, South)]);
+    }
+}
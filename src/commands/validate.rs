@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::Path;
+use log::{error, info, warn};
+
+use crate::commands::analyze_topology::{measured_hot_ports, place_cores, recorded_clusters, CorePlacement};
+use crate::error::Result;
+use crate::event::Event;
+use crate::topology::{NodeType, Topology};
+use crate::utils::{get_event, read_events_map};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Warning,
+    Error
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Warning => "warning",
+            Severity::Error   => "error",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Violation of one of the rules checked by `validate`
+struct Finding {
+    severity: Severity,
+    rule: &'static str,
+    item: String,
+    message: String,
+}
+
+/// Check the rules every topology derived from determine-topology outputs has to obey, see `check`
+///
+/// Returns one `severity;rule;item;message` line per finding, every finding is logged as well
+pub fn validate(run_dir: &Path, cmn_dir: &Path, topology: &Topology) -> Result<Vec<String>> {
+    let events_map = read_events_map(run_dir.join("events.csv").as_path())?;
+    let port_events = [get_event(&events_map, "mxp_p0_dat_txflit_valid")?,
+                       get_event(&events_map, "mxp_p1_dat_txflit_valid")?];
+    let placements = place_cores(&measured_hot_ports(cmn_dir, topology.nodeid_length, &port_events)?);
+    let supported: BTreeSet<(u16, u16)> = Event::from_csv(cmn_dir.join("mxp.csv").as_path(), topology.nodeid_length)?
+        .iter()
+        .filter(|e| e.counts >= 0)
+        .map(|e| (e.node_id.x, e.node_id.y))
+        .collect();
+
+    let mut findings = check(topology, &recorded_clusters(cmn_dir)?, &placements, &supported);
+    findings.sort_by_key(|f| std::cmp::Reverse(f.severity));
+    let mut lines = vec![String::from("severity;rule;item;message")];
+    for f in findings {
+        match f.severity {
+            Severity::Warning => warn!("[{}] {}: {}", f.rule, f.item, f.message),
+            Severity::Error => error!("[{}] {}: {}", f.rule, f.item, f.message),
+        }
+        lines.push(format!("{};{};{};{}", f.severity, f.rule, f.item, f.message));
+    }
+    info!("CMN {}: {} findings", topology.cmn_idx, lines.len() - 1);
+    Ok(lines)
+}
+
+/// Findings of the rules
+///  - the measurements of every DSU (CPU cluster) agree on exactly one XP port (`dsu_placement`), as given by the
+///    placements of its measured cores; without clusters, no CPU has been placed on several ports
+///  - no port is both HN-F and RN-F (`hnf_rnf`), nor classified as other conflicting node types (`node_type`)
+///  - the number of RN-F ports matches the number of clusters online during the run (`dsu_count`)
+///  - the mesh has no holes, i.e. XPs inside the mesh that reported <not supported> in mxp.csv (`mesh_holes`)
+fn check(topology: &Topology, clusters: &[Vec<u16>], placements: &BTreeMap<u16, CorePlacement>,
+         supported: &BTreeSet<(u16, u16)>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut find = |severity: Severity, rule: &'static str, item: String, message: String| {
+        findings.push(Finding { severity, rule, item, message });
+    };
+
+    let locations: BTreeMap<u16, BTreeSet<(u16, u16, u16)>> = topology.xps.iter()
+        .flat_map(|xp| xp.ports.iter().flat_map(move |p| p.cpus.iter().map(move |cpu| (*cpu, (xp.x, xp.y, p.port)))))
+        .fold(BTreeMap::new(), |mut locations, (cpu, location)| {
+            locations.entry(cpu).or_default().insert(location);
+            locations
+        });
+    let rnf_ports: BTreeSet<(u16, u16, u16)> = locations.values().flatten().copied().collect();
+
+    for cluster in clusters {
+        let item = format!("cpus {}", cluster.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(","));
+        let measured: Vec<&CorePlacement> = cluster.iter().filter_map(|cpu| placements.get(cpu)).collect();
+        let ports: BTreeSet<_> = measured.iter().filter_map(|p| match p {
+            CorePlacement::Unique(location) => Some(*location),
+            _ => None
+        }).collect();
+        let ambiguous = measured.iter().find_map(|p| match p {
+            CorePlacement::Ambiguous(candidates) => Some(candidates),
+            _ => None
+        });
+        if measured.is_empty() {
+            find(Severity::Warning, "dsu_placement", item, String::from("DSU has not been measured"));
+        } else if measured.contains(&&CorePlacement::Contradictory) {
+            find(Severity::Error, "dsu_placement", item, String::from("Measurements of the DSU fit no port"));
+        } else if ports.len() > 1 {
+            find(Severity::Error, "dsu_placement", item,
+                 format!("Measurements of the DSU's cores place them on ports {:?}", ports));
+        } else if let Some(candidates) = ambiguous {
+            find(Severity::Warning, "dsu_placement", item, format!("Measurements of the DSU fit ports {candidates:?}"));
+        }
+    }
+    if clusters.is_empty() {
+        for (cpu, ports) in locations.iter().filter(|(_, ports)| ports.len() > 1) {
+            find(Severity::Error, "dsu_placement", format!("cpu {cpu}"), format!("CPU sits on ports {:?}", ports));
+        }
+    }
+
+    for xp in &topology.xps {
+        for p in xp.ports.iter().filter(|p| !p.conflicting_types.is_empty()) {
+            let types: BTreeSet<NodeType> = p.conflicting_types.iter().chain([&p.node_type]).copied().collect();
+            let item = format!("{},{},{}", xp.x, xp.y, p.port);
+            match types.contains(&NodeType::HnF) && types.contains(&NodeType::RnF) {
+                true => find(Severity::Error, "hnf_rnf", item,
+                             String::from("Port is HN-F, but CPUs have been placed on it")),
+                false => find(Severity::Error, "node_type", item, format!("Port is classified as {}, but also as {}",
+                    p.node_type, p.conflicting_types.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ")))
+            }
+        }
+    }
+
+    if !clusters.is_empty() && clusters.len() != rnf_ports.len() {
+        find(Severity::Error, "dsu_count", format!("{} clusters", clusters.len()),
+             format!("{} clusters were online, but {} RN-F ports have been found", clusters.len(), rnf_ports.len()));
+    }
+
+    for x in 0..topology.mesh_x {
+        for y in 0..topology.mesh_y {
+            if !supported.contains(&(x, y)) {
+                find(Severity::Error, "mesh_holes", format!("{x},{y}"),
+                     format!("XP is inside the {}x{} mesh, but reported <not supported>", topology.mesh_x, topology.mesh_y));
+            }
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::{Port, Xp};
+    use CorePlacement::*;
+
+    /// 2x1 mesh with two DSUs on the ports of XP (0,0), and an HN-F at (1,0,0)
+    fn topology() -> Topology {
+        let port = |port, node_type, cpus: &[u16]| Port {
+            port, node_type, cpus: cpus.to_vec(), conflicting_types: Vec::new()
+        };
+        Topology { cmn_idx: 0, nodeid_length: 7, mesh_x: 2, mesh_y: 1, xps: vec![
+            Xp { x: 0, y: 0, ports: vec![port(0, NodeType::RnF, &[0, 1]), port(1, NodeType::RnF, &[2, 3])] },
+            Xp { x: 1, y: 0, ports: vec![port(0, NodeType::HnF, &[]), port(1, NodeType::XpOnly, &[])] },
+        ]}
+    }
+
+    fn clusters() -> Vec<Vec<u16>> {
+        vec![vec![0, 1], vec![2, 3]]
+    }
+
+    fn placements() -> BTreeMap<u16, CorePlacement> {
+        BTreeMap::from([(0, Unique((0, 0, 0))), (2, Unique((0, 0, 1)))])
+    }
+
+    fn supported() -> BTreeSet<(u16, u16)> {
+        BTreeSet::from([(0, 0), (1, 0)])
+    }
+
+    fn rules(findings: Vec<Finding>) -> Vec<(Severity, &'static str, String)> {
+        findings.into_iter().map(|f| (f.severity, f.rule, f.item)).collect()
+    }
+
+    #[test]
+    fn valid() {
+        assert!(check(&topology(), &clusters(), &placements(), &supported()).is_empty());
+    }
+
+    #[test]
+    fn dsu_placement() {
+        let check_placements = |placements| rules(check(&topology(), &clusters(), &placements, &supported()));
+        let dsu_0 = String::from("cpus 0,1");
+
+        // both cores of the first DSU have been measured, on different ports
+        let mut spread = placements();
+        spread.insert(1, Unique((1, 0, 1)));
+        assert_eq!(check_placements(spread), vec![(Severity::Error, "dsu_placement", dsu_0.clone())]);
+
+        let mut contradictory = placements();
+        contradictory.insert(0, Contradictory);
+        assert_eq!(check_placements(contradictory), vec![(Severity::Error, "dsu_placement", dsu_0.clone())]);
+
+        let mut ambiguous = placements();
+        ambiguous.insert(0, Ambiguous(BTreeSet::from([(0, 0, 0), (1, 0, 1)])));
+        assert_eq!(check_placements(ambiguous), vec![(Severity::Warning, "dsu_placement", dsu_0)]);
+
+        let mut unmeasured = placements();
+        unmeasured.remove(&2);
+        assert_eq!(check_placements(unmeasured), vec![(Severity::Warning, "dsu_placement", String::from("cpus 2,3"))]);
+
+        // without clusters, overlapping cores_per_dsu ranges can put a CPU on two ports
+        let mut topology = topology();
+        topology.xps[0].ports[1].cpus.push(1);
+        assert_eq!(rules(check(&topology, &[], &placements(), &supported())),
+                   vec![(Severity::Error, "dsu_placement", String::from("cpu 1"))]);
+    }
+
+    #[test]
+    fn node_types() {
+        let mut topology = topology();
+        topology.xps[0].ports[0].conflicting_types.push(NodeType::HnF);
+        topology.xps[1].ports[0].conflicting_types.push(NodeType::HnI);
+        assert_eq!(rules(check(&topology, &clusters(), &placements(), &supported())),
+                   vec![(Severity::Error, "hnf_rnf", String::from("0,0,0")),
+                        (Severity::Error, "node_type", String::from("1,0,0"))]);
+    }
+
+    #[test]
+    fn dsu_count() {
+        let clusters = [clusters(), vec![vec![4, 5]]].concat();
+        let mut placements = placements();
+        placements.insert(4, Unique((0, 0, 1)));
+        assert_eq!(rules(check(&topology(), &clusters, &placements, &supported())),
+                   vec![(Severity::Error, "dsu_count", String::from("3 clusters"))]);
+    }
+
+    #[test]
+    fn run_findings() {
+        let run_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/run_3x2");
+        let cmn_dir = run_dir.join("cmn_0");
+        let topology = crate::commands::analyze_topology::build_topology(run_dir.as_path(), 0, None, None).unwrap();
+        assert_eq!(validate(run_dir.as_path(), cmn_dir.as_path(), &topology).unwrap(),
+                   ["severity;rule;item;message", "error;hnf_rnf;2,0,0;Port is HN-F, but CPUs have been placed on it"]);
+    }
+
+    #[test]
+    fn mesh_holes() {
+        assert_eq!(rules(check(&topology(), &clusters(), &placements(), &BTreeSet::from([(0, 0)]))),
+                   vec![(Severity::Error, "mesh_holes", String::from("1,0"))]);
+    }
+}
@@ -0,0 +1,216 @@
+/* Parsing of the mesh map the arm-cmn driver exposes in debugfs, used as second source of the topology */
+use std::path::PathBuf;
+use log::warn;
+use crate::topology::{NodeType, Topology};
+
+static DEBUGFS_PATH: &str = "/sys/kernel/debug/arm-cmn";
+
+/// Port of an XP as listed in the debugfs map
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugfsPort {
+    pub x: u16,
+    pub y: u16,
+    pub port: u16,
+    /// Device type as printed by the driver (e.g. `RN-F_B`, `HN-F`), empty if nothing is connected
+    pub device_type: String,
+    /// Logical IDs of the nodes on device 0 and 1 of this port
+    pub logids: [Option<u16>; 2],
+}
+
+impl DebugfsPort {
+    /// Classification as done by determine-nodes / analyze-topology
+    ///  None if the device cannot be observed by the counters used there (e.g. SN-F, SBSX),
+    ///  or if only the logical ID of an internal node is known
+    pub fn node_type(&self) -> Option<NodeType> {
+        match self.device_type.as_str() {
+            "" if self.logids.iter().all(|l| l.is_none()) => Some(NodeType::XpOnly),
+            t if t.starts_with("RN-F") => Some(NodeType::RnF),
+            "RN-I" | "RN-D" => Some(NodeType::RnD),
+            "HN-I" | "HN-D" | "HN-P" | "HN-T" => Some(NodeType::HnI),
+            "HN-F" | "HN-S" => Some(NodeType::HnF),
+            "CXRA" | "CXHA" | "CXLA" | "CCRA" | "CCHA" | "CCLA" => Some(NodeType::Cxg),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DebugfsMap {
+    pub mesh_x: u16,
+    pub mesh_y: u16,
+    pub ports: Vec<DebugfsPort>,
+}
+
+/// debugfs file of the given CMN, the driver names them `map`, `map_1`, `map_2`, ...
+pub fn map_path(cmn_idx: u8) -> PathBuf {
+    let mut path = PathBuf::from(DEBUGFS_PATH);
+    match cmn_idx {
+        0 => path.push("map"),
+        _ => path.push(format!("map_{cmn_idx}"))
+    }
+    path
+}
+
+/// Parse the output of arm_cmn_map_show() (drivers/perf/arm-cmn.c)
+///  Every mesh row consists of an `XP #n` line prefixed by its Y coordinate, a DTC line, and per port a line with the
+///   device type (prefix `  P  `) followed by one line of logical IDs per device (prefix `    D`).
+///  Cells are separated by `|`, the X coordinate is the index of the cell.
+pub fn parse(content: &str) -> DebugfsMap {
+    let mut map = DebugfsMap::default();
+    let mut y: Option<u16> = None;
+    let mut port: Option<u16> = None;
+
+    for line in content.lines() {
+        let Some((prefix, rest)) = line.split_once('|') else { continue };
+        let cells: Vec<&str> = rest.split('|').collect();
+        let cells = &cells[..cells.len() - usize::from(cells.last() == Some(&""))];
+        let Ok(idx) = prefix.trim().parse::<u16>() else { continue };
+
+        if cells.iter().any(|c| c.trim().starts_with("XP #")) {
+            // row header, e.g. `5    | XP #40 | XP #41 |`
+            y = Some(idx);
+            port = None;
+            map.mesh_y = map.mesh_y.max(idx + 1);
+            map.mesh_x = map.mesh_x.max(cells.len() as u16);
+        } else if prefix.ends_with(' ') {
+            // device type of port idx, e.g. `  0  | RN-F_B |  HN-F  |`
+            let Some(y) = y else { continue };
+            port = Some(idx);
+            for (x, cell) in cells.iter().enumerate() {
+                map.ports.push(DebugfsPort {
+                    x: x as u16, y, port: idx,
+                    device_type: String::from(cell.trim()),
+                    logids: [None, None]
+                });
+            }
+        } else if let (Some(y), Some(port)) = (y, port) {
+            // logical IDs of device idx, e.g. `    0|   #3   |        |`
+            if idx > 1 { continue }
+            for (x, cell) in cells.iter().enumerate() {
+                let logid = cell.trim().strip_prefix('#').and_then(|l| l.trim().parse().ok());
+                if let Some(p) = map.ports.iter_mut().find(|p| p.x == x as u16 && p.y == y && p.port == port) {
+                    p.logids[idx as usize] = logid;
+                }
+            }
+        }
+    }
+    map.ports.sort_by_key(|p| (p.x, p.y, p.port));
+    map
+}
+
+/// Compare the counter-based topology with the debugfs map, one `x;y;port;counters;debugfs` line per disagreement
+///  Ports whose debugfs device type cannot be observed by the counters are skipped.
+pub fn crosscheck(topology: &Topology, map: &DebugfsMap) -> Vec<String> {
+    if (topology.mesh_x, topology.mesh_y) != (map.mesh_x, map.mesh_y) {
+        warn!("Mesh size differs: {}x{} from counters, {}x{} from debugfs",
+              topology.mesh_x, topology.mesh_y, map.mesh_x, map.mesh_y);
+    }
+
+    let mut lines = vec![String::from("x;y;port;counters;debugfs")];
+    for p in &map.ports {
+        let Some(expected) = p.node_type() else { continue };
+        let measured = topology.xps.iter()
+            .find(|xp| xp.x == p.x && xp.y == p.y)
+            .and_then(|xp| xp.ports.iter().find(|port| port.port == p.port))
+            .map(|port| port.node_type.to_string())
+            .unwrap_or(String::from("missing"));
+        if measured != expected.to_string() {
+            warn!("Port ({},{},{}) is {measured} according to counters, but {expected} ({}) according to debugfs",
+                  p.x, p.y, p.port, p.device_type);
+            lines.push(format!("{};{};{};{measured};{}", p.x, p.y, p.port, p.device_type));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_port(map: &DebugfsMap, x: u16, y: u16, port: u16) -> &DebugfsPort {
+        map.ports.iter().find(|p| p.x == x && p.y == y && p.port == port).unwrap()
+    }
+
+    #[test]
+    fn parse_altra_map() {
+        let map = parse(include_str!("../tests/data/debugfs_map_8x6.txt"));
+        assert_eq!((map.mesh_x, map.mesh_y), (8, 6));
+        assert_eq!(map.ports.len(), 8 * 6 * 2);
+
+        let p = get_port(&map, 0, 0, 0);
+        assert_eq!(p.device_type, "HN-D");
+        assert_eq!(p.node_type(), Some(NodeType::HnI));
+
+        let p = get_port(&map, 1, 0, 0);
+        assert_eq!(p.device_type, "RN-F_B");
+        assert_eq!(p.node_type(), Some(NodeType::RnF));
+
+        let p = get_port(&map, 2, 1, 1);
+        assert_eq!(p.device_type, "HN-F");
+        assert_eq!(p.logids, [Some(3), None]);
+
+        let p = get_port(&map, 0, 3, 0);
+        assert_eq!(p.device_type, "SN-F");
+        assert_eq!(p.node_type(), None);
+
+        let p = get_port(&map, 7, 5, 0);
+        assert_eq!(p.device_type, "CXRA");
+        assert_eq!(p.node_type(), Some(NodeType::Cxg));
+
+        let p = get_port(&map, 7, 5, 1);
+        assert_eq!(p.device_type, "");
+        assert_eq!(p.node_type(), Some(NodeType::XpOnly));
+    }
+
+    #[test]
+    fn parse_small_map() {
+        let map = parse(include_str!("../tests/data/debugfs_map_3x2.txt"));
+        assert_eq!((map.mesh_x, map.mesh_y), (3, 2));
+
+        let p = get_port(&map, 0, 1, 0);
+        assert_eq!(p.device_type, "RN-D");
+        assert_eq!(p.logids, [Some(0), Some(1)]);
+        assert_eq!(p.node_type(), Some(NodeType::RnD));
+
+        let p = get_port(&map, 2, 0, 1);
+        assert_eq!(p.device_type, "HN-P");
+        assert_eq!(p.node_type(), Some(NodeType::HnI));
+
+        // internal node without device type: logical ID known, but type is not
+        let p = get_port(&map, 1, 1, 1);
+        assert_eq!(p.device_type, "");
+        assert_eq!(p.logids, [Some(2), None]);
+        assert_eq!(p.node_type(), None);
+    }
+
+    #[test]
+    fn parse_garbage() {
+        let map = parse("no map here\n|||\n");
+        assert_eq!((map.mesh_x, map.mesh_y), (0, 0));
+        assert!(map.ports.is_empty());
+    }
+
+    #[test]
+    fn crosscheck_small_map() {
+        use crate::topology::{Port, Xp};
+
+        let map = parse(include_str!("../tests/data/debugfs_map_3x2.txt"));
+        let port = |port, node_type| Port { port, node_type, cpus: Vec::new(), conflicting_types: Vec::new() };
+        let xps = map.ports.chunks(2).map(|p| Xp {
+            x: p[0].x, y: p[0].y,
+            ports: p.iter().map(|p| port(p.port, p.node_type().unwrap_or(NodeType::XpOnly))).collect()
+        }).collect::<Vec<Xp>>();
+        let mut topology = Topology { cmn_idx: 0, nodeid_length: 7, mesh_x: 3, mesh_y: 2, xps };
+        assert_eq!(crosscheck(&topology, &map).len(), 1);
+
+        topology.xps[0].ports[0].node_type = NodeType::HnF;
+        topology.xps.pop();
+        assert_eq!(crosscheck(&topology, &map)[1..], ["0;0;0;HN-F;RN-F_A", "2;1;0;missing;HN-F", "2;1;1;missing;"]);
+    }
+
+    #[test]
+    fn map_paths() {
+        assert_eq!(map_path(0), PathBuf::from("/sys/kernel/debug/arm-cmn/map"));
+        assert_eq!(map_path(1), PathBuf::from("/sys/kernel/debug/arm-cmn/map_1"));
+    }
+}
@@ -0,0 +1,75 @@
+/* Errors of the measurement tool, every kind exits with its own code */
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// A sysfs file (PMU, CPU or NUMA description) could not be read, e.g. as the arm-cmn driver is not loaded
+    #[error("Could not read {}: {source} (is the arm-cmn driver loaded?)", path.display())]
+    Sysfs { path: PathBuf, source: io::Error },
+
+    /// Opening the CMN PMU events was refused
+    #[error("No permission to open perf events, run as root or set kernel.perf_event_paranoid to -1 \
+             (`sysctl kernel.perf_event_paranoid=-1`)")]
+    PerfPermission,
+
+    /// A program (perf, the benchmark, a launched application) could not be run
+    #[error("Could not run `{program}`: {source}")]
+    Command { program: String, source: io::Error },
+
+    /// perf ran, but its counts are unusable
+    #[error("{0}")]
+    Perf(String),
+
+    /// Unexpected contents of sysfs, perf reports, benchmark output or previously recorded data
+    #[error("Could not parse {what}: `{value}`")]
+    Parse { what: String, value: String },
+
+    /// Invalid arguments or config file
+    #[error("{0}")]
+    Config(String),
+
+    /// Reading input or writing output files failed
+    #[error("Could not access {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl Error {
+    /// Exit code of the error kind
+    ///  1 is taken by diff-topology (topologies differ), 2 by clap (invalid usage), 101 by panics.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 3,
+            Error::Sysfs { .. } => 4,
+            Error::PerfPermission => 5,
+            Error::Command { .. } | Error::Perf(_) => 6,
+            Error::Parse { .. } => 7,
+            Error::Io { .. } => 8,
+        }
+    }
+
+    pub fn parse(what: impl Into<String>, value: impl Into<String>) -> Error {
+        Error::Parse { what: what.into(), value: value.into() }
+    }
+
+    /// For `map_err` of IO on the given file
+    pub fn io(path: impl AsRef<Path>) -> impl FnOnce(io::Error) -> Error {
+        let path = path.as_ref().to_path_buf();
+        move |source| Error::Io { path, source }
+    }
+
+    /// For `map_err` of reading the given sysfs file
+    pub fn sysfs(path: impl AsRef<Path>) -> impl FnOnce(io::Error) -> Error {
+        let path = path.as_ref().to_path_buf();
+        move |source| Error::Sysfs { path, source }
+    }
+
+    /// For `map_err` of running the given program
+    pub fn command(program: impl AsRef<std::ffi::OsStr>) -> impl FnOnce(io::Error) -> Error {
+        let program = program.as_ref().to_string_lossy().to_string();
+        move |source| Error::Command { program, source }
+    }
+}
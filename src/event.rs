@@ -1,8 +1,12 @@
 /* CMN Perf event wrapping and handling */
+use std::path::Path;
 use serde::{Deserialize, Serialize, Serializer};
 use serde::ser::SerializeStruct;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::error::Error;
+use crate::perf::Count;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeID {
     pub x: u16,
     pub y: u16,
@@ -22,7 +26,7 @@ impl NodeID {
         assert!(self.y < 2_u16.pow(self.nodeid_length as u32));
         assert!(self.port <= 1);
 
-        (self.x << offset+3) | (self.y << 3) | (self.port << 2)
+        (self.x << (offset+3)) | (self.y << 3) | (self.port << 2)
     }
 
     pub fn from_nodeid(nodeid: u16, nodeid_length: u8) -> NodeID {
@@ -47,38 +51,123 @@ pub struct Event {
     pub event_type: u8,
     pub event_id: u16,
     pub node_id: NodeID,
-    pub counts: i128 // typical perf counters are unsigned 64-bit, but we need -1 for <not supported>, so go one step higher
+    pub counts: i128, // typical perf counters are unsigned 64-bit, but we need -1 for <not supported>, so go one step higher
+    /// Time (ns) the event was enabled, and actually scheduled on a counter; if less, counts have been scaled up
+    pub time_enabled: u64,
+    pub time_running: u64,
+    /// Pass the event was counted in, if the events did not fit the counters at once
+    pub pass: usize,
+}
+
+/// Row of an events CSV as written by `Writer::write_events`
+#[derive(Debug, Deserialize)]
+struct EventRecord {
+    cmn_idx: u8,
+    event_type: String,
+    event_id: String,
+    node_id: String,
+    counts: i128,
+    // not present in CSVs written before multiplexing was recorded
+    #[serde(default)]
+    time_enabled: u64,
+    #[serde(default)]
+    time_running: u64,
+    #[serde(default)]
+    pass: usize,
 }
 
 impl Event {
-    pub fn from_captures(c: regex::Captures, nodeid_length: u8) -> Event {
-        let counts = c.get(1).unwrap().as_str();
-        let counts_i = match counts.trim() {
-            "<not counted>" => 0,
-            "<not supported>" => -1,
-            _ => counts.parse::<i128>().unwrap()
-        };
-        Event {
-            cmn_idx: c.get(2).unwrap().as_str().parse().unwrap(),
-            event_type: u8::from_str_radix(c.get(3).unwrap().as_str().trim_start_matches("0x"), 16).unwrap(),
-            event_id: u16::from_str_radix(c.get(4).unwrap().as_str().trim_start_matches("0x"), 16).unwrap(),
-            node_id: NodeID::from_nodeid(u16::from_str_radix(c.get(5).unwrap().as_str().trim_start_matches("0x"), 16)
-                                                            .unwrap(),
-                                         nodeid_length),
-            counts: counts_i
+    /// Event from the captures (CMN index, type, event ID, node ID) of a perf event string
+    pub fn from_captures(c: regex::Captures, count: &Count, nodeid_length: u8) -> crate::error::Result<Event> {
+        let invalid = || Error::parse("perf event", count.event.as_str());
+        let hex = |i: usize| u16::from_str_radix(c[i].trim_start_matches("0x"), 16).map_err(|_| invalid());
+        Ok(Event {
+            cmn_idx: c[1].parse().map_err(|_| invalid())?,
+            event_type: u8::try_from(hex(2)?).map_err(|_| invalid())?,
+            event_id: hex(3)?,
+            node_id: NodeID::from_nodeid(hex(4)?, nodeid_length),
+            counts: count.counts,
+            time_enabled: count.time_enabled,
+            time_running: count.time_running,
+            pass: count.pass,
+        })
+    }
+
+    /// Read back an events CSV written by `Writer::write_events`
+    pub fn from_csv(path: &Path, nodeid_length: u8) -> crate::error::Result<Vec<Event>> {
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .delimiter(b';')
+            .from_path(path)
+            .map_err(|e| Error::parse(format!("{}", path.display()), e.to_string()))?;
+        let hex = |value: &str| u16::from_str_radix(value.trim_start_matches("0x"), 16)
+            .map_err(|_| Error::parse(format!("hex value in {}", path.display()), value));
+        csv_reader.deserialize::<EventRecord>()
+            .map(|r| {
+                let r = r.map_err(|e| Error::parse(format!("event in {}", path.display()), e.to_string()))?;
+                Ok(Event {
+                    cmn_idx: r.cmn_idx,
+                    event_type: u8::from_str_radix(r.event_type.trim_start_matches("0x"), 16)
+                        .map_err(|_| Error::parse(format!("event type in {}", path.display()), r.event_type.as_str()))?,
+                    event_id: hex(&r.event_id)?,
+                    node_id: NodeID::from_nodeid(hex(&r.node_id)?, nodeid_length),
+                    counts: r.counts,
+                    time_enabled: r.time_enabled,
+                    time_running: r.time_running,
+                    pass: r.pass,
+                })
+            })
+            .collect()
+    }
+
+    /// Share of the time enabled the event was actually counted, 1 if not multiplexed (or never enabled)
+    pub fn running_ratio(&self) -> f64 {
+        match self.time_enabled {
+            0 => 1.0,
+            _ => self.time_running as f64 / self.time_enabled as f64
         }
     }
 }
 
+/// Columns of an events CSV, in the order an `Event` is serialized
+pub const EVENT_COLUMNS: [&str; 9] = ["cmn_idx", "event_type", "event_id", "node_id", "counts", "time_enabled",
+                                      "time_running", "running_ratio", "pass"];
+
+impl Event {
+    fn serialize_fields<S: SerializeStruct>(&self, state: &mut S) -> Result<(), S::Error> {
+        let [cmn_idx, event_type, event_id, node_id, counts, time_enabled, time_running, running_ratio, pass]
+            = EVENT_COLUMNS;
+        state.serialize_field(cmn_idx, &self.cmn_idx)?;
+        state.serialize_field(event_type, &format!("{:#x}", self.event_type))?;
+        state.serialize_field(event_id, &format!("{:#x}", self.event_id))?;
+        state.serialize_field(node_id, &format!("{:#x}", self.node_id.to_nodeid()))?;
+        state.serialize_field(counts, &self.counts)?;
+        state.serialize_field(time_enabled, &self.time_enabled)?;
+        state.serialize_field(time_running, &self.time_running)?;
+        state.serialize_field(running_ratio, &format!("{:.4}", self.running_ratio()))?;
+        state.serialize_field(pass, &self.pass)
+    }
+}
+
 impl Serialize for Event {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
-        let mut state = serializer.serialize_struct("Event", 5)?;
-        state.serialize_field("cmn_idx", &self.cmn_idx)?;
-        state.serialize_field("event_type", &format!("{:#x}", self.event_type))?;
-        state.serialize_field("event_id", &format!("{:#x}", self.event_id))?;
-        state.serialize_field("node_id", &format!("{:#x}", self.node_id.to_nodeid()))?;
-        state.serialize_field("counts", &self.counts)?;
+        let mut state = serializer.serialize_struct("Event", EVENT_COLUMNS.len())?;
+        self.serialize_fields(&mut state)?;
+        state.end()
+    }
+}
+
+/// Row of a time series CSV as written by `Writer::write_time_series`: an event of an interval, prefixed by the time (s)
+///  since counting started
+pub struct TimedEvent<'a> {
+    pub time_s: f64,
+    pub event: &'a Event,
+}
 
+impl Serialize for TimedEvent<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut state = serializer.serialize_struct("TimedEvent", EVENT_COLUMNS.len() + 1)?;
+        state.serialize_field("time_s", &format!("{:.3}", self.time_s))?;
+        self.event.serialize_fields(&mut state)?;
         state.end()
     }
 }
\ No newline at end of file
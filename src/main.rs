@@ -1,49 +1,169 @@
 mod args;
 
 mod commands;
+mod debugfs;
+mod error;
 mod utils;
 mod event;
+mod perf;
+mod topology;
 mod writer;
 
-use std::path::Path;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
 use clap::Parser;
-use log::info;
+use log::{error, info, warn};
+use crate::error::{Error, Result};
+use crate::perf::{PerfBackend, PerfCli, PerfNative, Scheduler, XP_COUNTERS};
+use crate::topology::MeshInfo;
 use crate::writer::Writer;
 
+/// Mesh of every CMN, as given via --nodeid-length/--mesh-x/--mesh-y or detected otherwise
+fn get_meshes(args: &args::Cli, cmn_indices: &[u8], perf: &dyn PerfBackend) -> Result<Vec<MeshInfo>> {
+    cmn_indices.iter().map(|cmn_idx| {
+        match (args.nodeid_length, args.mesh_x, args.mesh_y) {
+            (Some(nodeid_length), Some(mesh_x), Some(mesh_y)) => Ok(MeshInfo { cmn_idx: *cmn_idx, nodeid_length, mesh_x, mesh_y }),
+            _ => {
+                let (mesh, _) = commands::determine_mesh::detect(*cmn_idx, args.nodeid_length, perf)?;
+                Ok(MeshInfo { mesh_x: args.mesh_x.unwrap_or(mesh.mesh_x), mesh_y: args.mesh_y.unwrap_or(mesh.mesh_y), ..mesh })
+            }
+        }
+    }).collect()
+}
+
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
     let args = args::Cli::parse();
-    let basepath = if args.outdir == "" { None } else { Some(args.outdir.clone()) };
+    if let args::Commands::Benchmark(bargs) = &args.command {
+        // runs under perf stat, started by determine-topology, so there is nothing to write
+        cmn_benchmark::run(bargs.clone());
+        return
+    }
+    match run(&args) {
+        Ok(exit_code) => std::process::exit(exit_code),
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(e.exit_code());
+        }
+    }
+}
 
-    let mut writer = Writer::new(basepath, &args);
-    if args.events.is_some() || matches!(args.command, args::Commands::DetermineTopology(_)) {
-        writer.write_lines(utils::events_map_to_vec(), "events.csv");
+/// Run the command, returning the exit code
+fn run(args: &args::Cli) -> Result<i32> {
+    let basepath = if args.outdir.is_empty() { None } else { Some(args.outdir.clone()) };
+
+    let backend: Box<dyn PerfBackend> = match args.perf_backend {
+        args::PerfBackendKind::Cli => Box::new(PerfCli { strict: args.strict_multiplexing }),
+        args::PerfBackendKind::Native => Box::new(PerfNative { strict: args.strict_multiplexing })
+    };
+    if args.strict_multiplexing && matches!(args.event_schedule, args::EventSchedule::Rotate) {
+        return Err(Error::Config(String::from("Rotating through the passes multiplexes them, which \
+                                               --strict-multiplexing refuses")))
+    }
+    if matches!((args.event_schedule, args.perf_backend), (args::EventSchedule::Rotate, args::PerfBackendKind::Cli)) {
+        // every slice would start a perf process, whose startup takes most of the slice
+        return Err(Error::Config(String::from("Rotating through the passes needs --perf-backend native")))
+    }
+    if matches!(&args.command, args::Commands::DetermineTopology(dargs)
+                if dargs.dsu_pairs.is_some() && !matches!(dargs.core_sweep, args::CoreSweep::Pairs)) {
+        // checked up front, the core placement is only determined after the mesh, nodes and memory
+        return Err(Error::Config(String::from("--dsu-pairs needs --core-sweep pairs")))
+    }
+    let perf = Scheduler {
+        backend,
+        schedule: args.event_schedule,
+        rotate_interval: Duration::from_millis(args.rotate_interval)
+    };
+    let perf = &perf;
+
+    let cmn_indices = utils::get_cmn_indices();
+    let is_analysis = matches!(args.command, args::Commands::AnalyzeTopology(_) | args::Commands::RouteCheck(_)
+                                             | args::Commands::DiffTopology(_));
+    if cmn_indices.is_empty() && !is_analysis {
+        return Err(Error::Sysfs {
+            path: PathBuf::from(format!("{}/arm_cmn_0", utils::PMU_SYSFS_PATH)),
+            source: io::ErrorKind::NotFound.into()
+        })
+    }
+    let mut writer = Writer::new(basepath, args)?;
+    let events = match args.preset {
+        Some(args::EventPreset::CrossSocket) => {
+            let preset = commands::cross_socket::preset_events(cmn_indices.first().copied());
+            Some([args.events.clone().unwrap_or_default(), preset].concat())
+        }
+        None => args.events.clone()
+    };
+    if let (Some(events), args::EventSchedule::All) = (&events, args.event_schedule) {
+        // every event is counted on every XP, on both ports if prefixed with 01
+        let per_xp: usize = events.iter().map(|e| match e.split_once(':') {
+            Some(("01" | "10", _)) => 2,
+            _ => 1
+        }).sum();
+        if per_xp > XP_COUNTERS {
+            warn!("{per_xp} events per XP exceed its {XP_COUNTERS} counters, the kernel will multiplex them \
+                   (see --event-schedule passes or rotate)");
+        }
+    }
+    let interval = args.interval_ms.map(Duration::from_millis);
+    if events.is_some() || matches!(args.command, args::Commands::DetermineTopology(_)) {
+        // event types are identical for all CMNs of a system
+        if let Some(cmn_idx) = cmn_indices.first() {
+            writer.write_lines(utils::events_map_to_vec(*cmn_idx)?, "events.csv", None)?;
+        }
     }
 
     if writer.basepath.is_some() {
         info!("Will write data to: {:?}", writer.get_outpath());
     }
 
+    let mut exit_code = 0;
     match &args.command {
         args::Commands::DetermineTopology(dargs) => {
-            let mesh_size = commands::determine_mesh::determine(args.nodeid_length, &writer);
-            commands::determine_nodes::determine(args.nodeid_length, mesh_size, &writer);
-            commands::determine_cores::determine(args.nodeid_length, mesh_size, args.cores_per_dsu as u16,
-                                                 Path::new(&dargs.benchmark_binary_path),
-                                                 dargs.benchmark_binary_args.clone(),  &writer);
+            for cmn_idx in cmn_indices {
+                let mesh = commands::determine_mesh::determine(cmn_idx, args.nodeid_length, perf, &writer)?;
+                let mesh_size = (mesh.mesh_x, mesh.mesh_y);
+                let nodes = commands::determine_nodes::determine(cmn_idx, mesh.nodeid_length, mesh_size, perf,
+                                                                 &writer)?;
+                commands::determine_memory::determine(cmn_idx, mesh.nodeid_length, mesh_size, dargs, perf, &writer)?;
+                if dargs.hash_lines > 0 {
+                    commands::determine_hash::determine(cmn_idx, mesh.nodeid_length, &nodes, dargs, perf, &writer)?;
+                }
+                commands::determine_cores::determine(cmn_idx, mesh.nodeid_length, mesh_size, args.cores_per_dsu,
+                                                     dargs, perf, &writer)?;
+            }
+        }
+
+        args::Commands::AnalyzeTopology(aargs) => {
+            commands::analyze_topology::analyze(aargs, args.nodeid_length, args.cores_per_dsu, &writer)?;
+        }
+
+        args::Commands::RouteCheck(rargs) => {
+            commands::route_check::route_check(rargs, args.nodeid_length, args.cores_per_dsu, &writer)?;
+        }
+
+        args::Commands::DiffTopology(dargs) => {
+            if commands::diff_topology::diff_topology(dargs, args.nodeid_length, args.cores_per_dsu, &writer)? {
+                exit_code = 1;
+            }
         }
 
         args::Commands::Launch(largs) => {
-           commands::launch::launch(largs, (args.mesh_x, args.mesh_y), args.nodeid_length,
-                                    args.events, &writer);
+            let meshes = get_meshes(args, &cmn_indices, perf)?;
+            let gateways = args.preset.map(|_| commands::cross_socket::locate_gateways(&meshes, perf)).transpose()?;
+            commands::launch::launch(largs, &meshes, events, gateways.as_ref(), interval, perf, &writer)?;
         }
 
         args::Commands::LaunchMulti(largs) => {
-            commands::launch_multi::launch_multi(largs, (args.mesh_x, args.mesh_y), args.nodeid_length,
-                                                 args.events, &mut writer);
+            let meshes = get_meshes(args, &cmn_indices, perf)?;
+            let gateways = args.preset.map(|_| commands::cross_socket::locate_gateways(&meshes, perf)).transpose()?;
+            commands::launch_multi::launch_multi(largs, &meshes, events, gateways.as_ref(), interval, perf,
+                                                 &mut writer)?;
         }
-    }
-    writer.write_meta();
 
+        args::Commands::Benchmark(_) => unreachable!("Benchmark is run before the writer is set up")
+    }
+    writer.write_meta()?;
+    Ok(exit_code)
 }
@@ -0,0 +1,221 @@
+/* perf stat backend: runs the perf binary and parses its CSV report */
+use std::process::{Child, Command, Output, Stdio};
+use std::time::Duration;
+use regex::Regex;
+
+use crate::error::{Error, Result};
+use crate::perf::{copy_environment, Count, Counts, Interval, PerfBackend, Session};
+
+/// Counts via `perf stat --field-separator ;`
+pub struct PerfCli {
+    /// Refuse multiplexed results
+    pub strict: bool,
+}
+
+/// `perf stat` counting the given events, printing the counts of every interval if given
+fn perf_stat(events: &[String], interval: Option<Duration>) -> Command {
+    let mut cmd = Command::new("perf");
+    cmd.arg("stat")
+        .arg("--field-separator")
+        .arg(";");
+    if let Some(interval) = interval {
+        cmd.arg("--interval-print").arg(interval.as_millis().to_string());
+    }
+    for event in events {
+        cmd.arg("-e").arg(event);
+    }
+    cmd
+}
+
+/// Mark the events that never got a counter (<not counted> or 0%) as not running
+///  perf reports no time enabled for them, but they were enabled as long as the other events of the same report.
+fn mark_not_running(counts: &mut [Count]) {
+    let time_enabled = counts.iter().map(|c| c.time_enabled).max().unwrap_or(0).max(1);
+    for count in counts.iter_mut().filter(|c| c.counts >= 0 && c.time_running == 0) {
+        count.time_enabled = time_enabled;
+    }
+}
+
+/// Counts of the CMN events in a perf stat report
+///  Every line is `counts;unit;event;time running;percentage of time enabled running;...`
+///  With `-I`, every line is prefixed by the time of its interval, and the totals are the sum of all intervals.
+fn parse_report(report: String) -> Counts {
+    let pattern = Regex::new(
+        r"^\s*(?:([\d.]+);)?(\d+|<not supported>|<not counted>);;(arm_cmn_\d+/.*?/);(\d*);([\d.]*)").unwrap();
+    let mut counts: Vec<Count> = Vec::new();
+    let mut intervals: Vec<Interval> = Vec::new();
+    for c in report.split("\n").filter_map(|l| pattern.captures(l)) {
+        let not_counted = c.get(2).unwrap().as_str() == "<not counted>";
+        let value = match c.get(2).unwrap().as_str() {
+            "<not counted>" => 0,
+            "<not supported>" => -1,
+            value => value.parse::<i128>().unwrap()
+        };
+        let percentage: f64 = c.get(5).unwrap().as_str().parse().unwrap_or(100.0);
+        let time_running: u64 = match not_counted || percentage == 0.0 {
+            true => 0, // not running, see mark_not_running
+            false => c.get(4).unwrap().as_str().parse().unwrap_or(0)
+        };
+        let time_enabled = match percentage {
+            0.0 | 100.0.. => time_running,
+            _ => (time_running as f64 * 100.0 / percentage) as u64
+        };
+        let count = Count { event: String::from(c.get(3).unwrap().as_str()), counts: value, time_enabled, time_running,
+                            pass: 0 };
+        match c.get(1) {
+            None => counts.push(count),
+            Some(time) => {
+                let elapsed = Duration::from_secs_f64(time.as_str().parse().unwrap());
+                if intervals.last().is_none_or(|i| i.elapsed != elapsed) {
+                    intervals.push(Interval { elapsed, counts: Vec::new() });
+                }
+                intervals.last_mut().unwrap().counts.push(count);
+            }
+        }
+    }
+
+    mark_not_running(&mut counts);
+    intervals.iter_mut().for_each(|i| mark_not_running(&mut i.counts));
+
+    // perf prints the events of every interval in the same order
+    if counts.is_empty() {
+        for interval in &intervals {
+            for (k, count) in interval.counts.iter().enumerate() {
+                let Some(total) = counts.get_mut(k) else {
+                    counts.push(count.clone());
+                    continue
+                };
+                if count.counts >= 0 {
+                    total.counts = total.counts.max(0) + count.counts;
+                }
+                total.time_enabled += count.time_enabled;
+                total.time_running += count.time_running;
+            }
+        }
+    }
+    Counts { counts, intervals, report }
+}
+
+/// Counts of the report, unless perf refused to count or the counts are multiplexed in strict mode
+fn check_report(counts: Counts, events: &[String], strict: bool) -> Result<Counts> {
+    if counts.counts.is_empty() && !events.is_empty() {
+        if counts.report.contains("perf_event_paranoid") || counts.report.contains("Permission denied") {
+            return Err(Error::PerfPermission)
+        }
+        return Err(Error::Perf(format!("perf reported no counts of the CMN events: `{}`", counts.report.trim())))
+    }
+    counts.check_multiplexing(strict)?;
+    Ok(counts)
+}
+
+impl PerfBackend for PerfCli {
+    /// Run `perf stat <events> <command>`, the command's stderr thus contains perf's report
+    fn stat(&self, events: &[String], command: Command, interval: Option<Duration>) -> Result<(Counts, Output)> {
+        let mut cmd = perf_stat(events, interval);
+        cmd.arg(command.get_program()).args(command.get_args());
+        copy_environment(&mut cmd, &command);
+        // like the command would when spawned, it reads from our stdin
+        let output = cmd.stdin(Stdio::inherit()).output().map_err(Error::command("perf"))?;
+        let counts = parse_report(String::from_utf8_lossy(&output.stderr).to_string());
+        Ok((check_report(counts, events, self.strict)?, output))
+    }
+
+    /// Run `perf stat <events>` without a command, which counts until it receives SIGINT
+    ///  With an interval, perf prints the counts of every interval (`-I`) instead of the totals.
+    fn start(&self, events: &[String], interval: Option<Duration>) -> Result<Box<dyn Session>> {
+        let mut cmd = perf_stat(events, interval);
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let perf = cmd.spawn().map_err(Error::command("perf"))?;
+        Ok(Box::new(CliSession { perf, events: events.to_vec(), strict: self.strict }))
+    }
+}
+
+struct CliSession {
+    perf: Child,
+    events: Vec<String>,
+    strict: bool,
+}
+
+impl Session for CliSession {
+    fn read(&mut self) -> Option<Counts> {
+        None
+    }
+
+    fn stop(self: Box<Self>) -> Result<Counts> {
+        // send ^C / SIGINT to perf
+        Command::new("kill")
+            .args(["-s", "INT", &self.perf.id().to_string()])
+            .status().map_err(Error::command("kill"))?;
+        let output = self.perf.wait_with_output().map_err(Error::command("perf"))?;
+        let counts = parse_report(String::from_utf8_lossy(&output.stderr).to_string());
+        check_report(counts, &self.events, self.strict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_stat_report() {
+        let counts = parse_report(String::from(include_str!("../../tests/data/perf_stat.txt")));
+        assert!(counts.intervals.is_empty());
+        // only CMN events are counted
+        assert_eq!(counts.counts.len(), 5);
+        let summary: Vec<(&str, i128, u64, u64)> = counts.counts.iter()
+            .map(|c| (c.event.as_str(), c.counts, c.time_enabled, c.time_running))
+            .collect();
+        assert_eq!(summary, [
+            ("arm_cmn_0/type=0x4,eventid=0x1,bynodeid=0x1,nodeid=0x8/", 1385020, 2003611840, 2003611840),
+            ("arm_cmn_0/type=0x4,eventid=0x2,bynodeid=0x1,nodeid=0x8/", 0, 2003610260, 2003610260),
+            ("arm_cmn_0/type=0x4,eventid=0x3,bynodeid=0x1,nodeid=0x8/", 8126, 2003611840, 1001805920),
+            ("arm_cmn_0/type=0x4,eventid=0x4,bynodeid=0x1,nodeid=0x8/", 0, 2003611840, 0),
+            ("arm_cmn_1/type=0x4,eventid=0x1,bynodeid=0x1,nodeid=0x10/", -1, 0, 0),
+        ]);
+        let multiplexed: Vec<bool> = counts.counts.iter().map(|c| c.is_multiplexed()).collect();
+        assert_eq!(multiplexed, [false, false, true, true, false]);
+    }
+
+    #[test]
+    fn parse_interval_report() {
+        let counts = parse_report(String::from(include_str!("../../tests/data/perf_stat_interval.txt")));
+        let elapsed: Vec<u128> = counts.intervals.iter().map(|i| i.elapsed.as_micros()).collect();
+        assert_eq!(elapsed, [100118, 200287, 253901]);
+        assert!(counts.intervals.iter().all(|i| i.counts.len() == 3));
+
+        // not counted during the first interval, but enabled as long as the other events
+        let count = &counts.intervals[0].counts[1];
+        assert_eq!((count.counts, count.time_enabled, count.time_running), (0, 100093640, 0));
+        let count = &counts.intervals[1].counts[1];
+        assert_eq!((count.counts, count.time_enabled, count.time_running), (612, 100150020, 50075010));
+
+        // totals are the sum of all intervals
+        let totals: Vec<(i128, u64, u64)> = counts.counts.iter()
+            .map(|c| (c.counts, c.time_enabled, c.time_running))
+            .collect();
+        assert_eq!(totals, [(4000, 253835770, 253835770), (729, 253835770, 103667120), (-1, 0, 0)]);
+        assert!(counts.counts[1].is_multiplexed());
+    }
+
+    #[test]
+    fn report_without_counts() {
+        let report = "Error:\nAccess to performance monitoring and observability operations is limited.\n\
+                      Consider adjusting /proc/sys/kernel/perf_event_paranoid setting\n";
+        let events = [String::from("arm_cmn_0/type=0x4,eventid=0x1,bynodeid=0x1,nodeid=0x8/")];
+        let result = check_report(parse_report(String::from(report)), &events, false);
+        assert!(matches!(result, Err(Error::PerfPermission)));
+
+        let result = check_report(parse_report(String::from("event syntax error\n")), &events, false);
+        assert!(matches!(result, Err(Error::Perf(_))));
+
+        // without events, there is nothing to count
+        assert!(check_report(parse_report(String::new()), &[], false).is_ok());
+    }
+
+    #[test]
+    fn strict_report() {
+        let counts = parse_report(String::from(include_str!("../../tests/data/perf_stat.txt")));
+        let events: Vec<String> = counts.counts.iter().map(|c| c.event.clone()).collect();
+        assert!(matches!(check_report(counts, &events, true), Err(Error::Perf(_))));
+    }
+}
@@ -0,0 +1,136 @@
+/* Backends counting CMN PMU events: the perf binary (perf stat), or perf_event_open directly */
+mod cli;
+mod native;
+mod schedule;
+
+use std::process::{Command, Output};
+use std::time::Duration;
+use log::warn;
+use regex::Regex;
+
+use crate::error::{Error, Result};
+use crate::event::Event;
+
+pub use cli::PerfCli;
+pub use native::PerfNative;
+pub use schedule::{Scheduler, XP_COUNTERS};
+
+/// Count of one perf event (as built by `utils::get_event_string`)
+#[derive(Debug, Clone)]
+pub struct Count {
+    pub event: String,
+    /// Like in the Event CSVs, <not supported> is -1 and <not counted> 0; scaled by time_enabled / time_running
+    pub counts: i128,
+    /// Time (ns) the event was enabled, and actually scheduled on a counter
+    pub time_enabled: u64,
+    pub time_running: u64,
+    /// Pass the event was counted in, if split into several (see `Scheduler`)
+    pub pass: usize,
+}
+
+impl Count {
+    /// Whether the event shared a counter with others (or never got one), so its counts are an estimate
+    pub fn is_multiplexed(&self) -> bool {
+        self.counts >= 0 && self.time_running < self.time_enabled
+    }
+
+    /// Share of the time enabled the event was actually counted, 1 if not multiplexed (or never enabled)
+    pub fn running_ratio(&self) -> f64 {
+        match self.time_enabled {
+            0 => 1.0,
+            _ => self.time_running as f64 / self.time_enabled as f64
+        }
+    }
+}
+
+/// Counts of the events during one interval, in the order they were given
+#[derive(Debug, Clone)]
+pub struct Interval {
+    /// Time since counting started, at the end of the interval
+    pub elapsed: Duration,
+    pub counts: Vec<Count>,
+}
+
+/// Counts of perf events, in the order they were given
+#[derive(Debug, Default)]
+pub struct Counts {
+    pub counts: Vec<Count>,
+    /// Counts of every interval, if counting was started with one
+    pub intervals: Vec<Interval>,
+    /// Report of the backend (the output of perf stat), empty for the native backend
+    pub report: String,
+}
+
+/// Events of the CMN event counts, other events are skipped
+fn to_events(counts: &[Count], nodeid_length: u8) -> Result<Vec<Event>> {
+    let pattern = Regex::new(r"^arm_cmn_(\d+)/type=(.*?),eventid=(.*?),bynodeid=0x1,nodeid=(.*?)/$").unwrap();
+    counts.iter()
+        .filter_map(|count| Some(Event::from_captures(pattern.captures(&count.event)?, count, nodeid_length)))
+        .collect()
+}
+
+impl Counts {
+    /// CMN events among the counts, node IDs decoded with the given node ID length
+    pub fn to_events(&self, nodeid_length: u8) -> Result<Vec<Event>> {
+        to_events(&self.counts, nodeid_length)
+    }
+
+    /// Events of every interval, along with the time (s) since counting started
+    pub fn interval_events(&self, nodeid_length: u8) -> Result<Vec<(f64, Vec<Event>)>> {
+        self.intervals.iter()
+            .map(|i| Ok((i.elapsed.as_secs_f64(), to_events(&i.counts, nodeid_length)?)))
+            .collect()
+    }
+
+    /// Warn about multiplexed events, whose counts have been scaled up from the time they were running
+    ///  In strict mode, multiplexed results are refused.
+    fn check_multiplexing(&self, strict: bool) -> Result<()> {
+        let multiplexed: Vec<&Count> = self.counts.iter().filter(|c| c.is_multiplexed()).collect();
+        let Some(worst) = multiplexed.iter().min_by(|a, b| a.running_ratio().total_cmp(&b.running_ratio())) else {
+            return Ok(())
+        };
+        let message = format!("{} of {} events were multiplexed, {} only ran {:.1}% of the time",
+                              multiplexed.len(), self.counts.len(), worst.event, worst.running_ratio() * 100.0);
+        match strict {
+            true => Err(Error::Perf(format!("{message}, refusing scaled counts (strict multiplexing), measure fewer \
+                                             events at once or use --event-schedule passes"))),
+            false => {
+                warn!("{message}, counts have been scaled");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Copy environment and working directory of `from` to `to`
+fn copy_environment(to: &mut Command, from: &Command) {
+    for (key, value) in from.get_envs() {
+        match value {
+            Some(value) => to.env(key, value),
+            None => to.env_remove(key)
+        };
+    }
+    if let Some(dir) = from.get_current_dir() {
+        to.current_dir(dir);
+    }
+}
+
+/// Way of counting PMU events system-wide
+pub trait PerfBackend {
+    /// Count the events while running `command` (until it exits), returning the counts and the output of the command
+    ///  With an interval, the counts of every interval are recorded as well (`Counts::intervals`).
+    fn stat(&self, events: &[String], command: Command, interval: Option<Duration>) -> Result<(Counts, Output)>;
+
+    /// Start counting the events, until the returned session is stopped
+    ///  With an interval, the counts of every interval are recorded as well (`Counts::intervals`).
+    fn start(&self, events: &[String], interval: Option<Duration>) -> Result<Box<dyn Session>>;
+}
+
+/// Events being counted
+pub trait Session {
+    /// Counts so far, while counting continues; None if the backend cannot read counters mid-run (or reading failed)
+    fn read(&mut self) -> Option<Counts>;
+
+    /// Stop counting and return the final counts
+    fn stop(self: Box<Self>) -> Result<Counts>;
+}
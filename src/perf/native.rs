@@ -0,0 +1,402 @@
+/* perf_event_open backend: opens the events directly, configured via the PMU's sysfs type and format files */
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use log::debug;
+use perf_event_open_sys as sys;
+use perf_event_open_sys::bindings::perf_event_attr;
+
+use crate::error::{Error, Result};
+use crate::perf::{Count, Counts, Interval, PerfBackend, Session};
+use crate::perf::schedule::XP_COUNTERS;
+use crate::utils::{read_sysfs, PMU_SYSFS_PATH};
+
+/// Maximum number of events per group, the number of counters of a CMN DTM (one per XP)
+const GROUP_SIZE: usize = XP_COUNTERS;
+/// How often the sampler checks whether it has been stopped, while waiting for the end of the interval
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Counts via perf_event_open(2), without the perf binary
+///  Events of the same XP are opened as group (up to GROUP_SIZE), so they are scheduled together and read at once.
+pub struct PerfNative {
+    /// Refuse multiplexed results
+    pub strict: bool,
+}
+
+/// PMU as described in sysfs
+struct Pmu {
+    pmu_type: u32,
+    /// CPU the (uncore) PMU counts on
+    cpu: i32,
+    /// Term name -> (config field, bit ranges), e.g. `nodeid` -> (0, [(32, 47)]) for `config:32-47`
+    formats: HashMap<String, (usize, Vec<(u32, u32)>)>,
+}
+
+impl Pmu {
+    fn load(name: &str) -> Result<Pmu> {
+        let path = format!("{PMU_SYSFS_PATH}/{name}");
+        // PMUs without cpumask count on any CPU, so take the first one
+        let cpu = fs::read_to_string(format!("{path}/cpumask")).unwrap_or_default()
+            .trim().split([',', '-']).next().unwrap().parse().unwrap_or(0);
+        let mut formats = HashMap::new();
+        let format_path = format!("{path}/format");
+        for file in fs::read_dir(&format_path).map_err(Error::sysfs(&format_path))? {
+            let file = file.map_err(Error::sysfs(&format_path))?;
+            formats.insert(file.file_name().to_string_lossy().to_string(),
+                           parse_format(read_sysfs(file.path())?.trim())?);
+        }
+        let pmu_type = read_sysfs(format!("{path}/type"))?;
+        let pmu_type = pmu_type.trim().parse().map_err(|_| Error::parse(format!("type of PMU {name}"), pmu_type.trim()))?;
+        Ok(Pmu { pmu_type, cpu, formats })
+    }
+
+    /// perf_event_attr of the event terms, e.g. `type=0x5,eventid=0x1,bynodeid=0x1,nodeid=0x8`
+    fn attr(&self, terms: &str) -> Result<perf_event_attr> {
+        let mut config = [0u64; 3];
+        for term in terms.split(',').filter(|t| !t.is_empty()) {
+            let (name, value) = term.split_once('=').unwrap_or((term, "1"));
+            let value = match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => value.parse()
+            }.map_err(|_| Error::Config(format!("Invalid value of event term `{term}`")))?;
+            let (field, ranges) = self.formats.get(name)
+                .ok_or_else(|| Error::Config(format!("Unknown event term `{name}`")))?;
+            let mut value = value;
+            for (low, high) in ranges {
+                for bit in *low..=*high {
+                    config[*field] |= (value & 1) << bit;
+                    value >>= 1;
+                }
+            }
+        }
+
+        let mut attr = perf_event_attr {
+            type_: self.pmu_type,
+            size: size_of::<perf_event_attr>() as u32,
+            config: config[0],
+            read_format: (sys::bindings::perf_event_read_format_PERF_FORMAT_GROUP
+                | sys::bindings::perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED
+                | sys::bindings::perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) as u64,
+            ..Default::default()
+        };
+        attr.__bindgen_anon_3.config1 = config[1];
+        attr.__bindgen_anon_4.config2 = config[2];
+        Ok(attr)
+    }
+}
+
+/// Format of a term as given in sysfs, e.g. `config:0-15` or `config1:0-7,16-23`
+fn parse_format(format: &str) -> Result<(usize, Vec<(u32, u32)>)> {
+    let invalid = || Error::parse("PMU format", format);
+    let (field, bits) = format.split_once(':').ok_or_else(invalid)?;
+    let field = match field {
+        "config" => 0,
+        "config1" => 1,
+        "config2" => 2,
+        _ => return Err(invalid())
+    };
+    let ranges = bits.split(',').map(|range| {
+        let (low, high) = range.split_once('-').unwrap_or((range, range));
+        Ok((low.parse().map_err(|_| invalid())?, high.parse().map_err(|_| invalid())?))
+    }).collect::<Result<_>>()?;
+    Ok((field, ranges))
+}
+
+/// Split `arm_cmn_0/type=0x5,eventid=0x1/` into PMU and terms
+fn split_event(event: &str) -> Result<(&str, &str)> {
+    let (pmu, terms) = event.split_once('/')
+        .ok_or_else(|| Error::Config(format!("Invalid event `{event}`, expected pmu/terms/")))?;
+    Ok((pmu, terms.trim_end_matches('/')))
+}
+
+/// Open one event, as member of the group led by `group`, errno if it could not be opened
+fn open(attr: &mut perf_event_attr, cpu: i32, group: Option<&File>) -> std::result::Result<File, i32> {
+    attr.set_disabled(group.is_none() as u64);
+    let group_fd = group.map(|g| g.as_raw_fd()).unwrap_or(-1);
+    let fd = unsafe { sys::perf_event_open(attr, -1, cpu, group_fd, sys::bindings::PERF_FLAG_FD_CLOEXEC as u64) };
+    match fd {
+        0.. => Ok(unsafe { File::from_raw_fd(fd) }),
+        // the raw syscall returns -1 and sets errno
+        _ => Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(0))
+    }
+}
+
+/// Whether opening failed as perf events are not accessible (kernel.perf_event_paranoid)
+fn is_permission(errno: i32) -> bool {
+    errno == libc::EACCES || errno == libc::EPERM
+}
+
+/// Events counted together, the first file is the group leader
+struct Group {
+    files: Vec<File>,
+    /// Index of the event of each file
+    events: Vec<usize>,
+}
+
+impl Group {
+    fn ioctl(&self, request: unsafe fn(i32, u32) -> i32) -> Result<()> {
+        let ret = unsafe { request(self.files[0].as_raw_fd(), sys::bindings::perf_event_ioc_flags_PERF_IOC_FLAG_GROUP) };
+        match ret {
+            0 => Ok(()),
+            _ => Err(Error::Perf(format!("ioctl on perf event failed: {}", std::io::Error::last_os_error())))
+        }
+    }
+
+    /// Counts of the group's events, scaled by time enabled / time running like perf stat does if multiplexed
+    ///  Read format: nr, time_enabled, time_running, value of every event
+    ///  Returns (event index, counts, time enabled, time running)
+    fn read(&self) -> Result<Vec<(usize, i128, u64, u64)>> {
+        let mut buffer = vec![0u8; (3 + self.files.len()) * size_of::<u64>()];
+        (&self.files[0]).read_exact(&mut buffer)
+            .map_err(|e| Error::Perf(format!("Could not read perf event group: {e}")))?;
+        let values: Vec<u64> = buffer.chunks_exact(8).map(|c| u64::from_ne_bytes(c.try_into().unwrap())).collect();
+        let (enabled, running) = (values[1], values[2]);
+        Ok(self.events.iter().zip(&values[3..]).map(|(event, value)| {
+            let counts = match running {
+                0 => 0, // <not counted>
+                _ => (*value as u128 * enabled as u128 / running as u128) as i128
+            };
+            (*event, counts, enabled, running)
+        }).collect())
+    }
+}
+
+/// Counts of all events, events not in any group are <not supported>
+fn read_counts(events: &[String], groups: &[Group]) -> Result<Vec<Count>> {
+    let mut counts: Vec<Count> = events.iter()
+        .map(|e| Count { event: e.clone(), counts: -1, time_enabled: 0, time_running: 0, pass: 0 })
+        .collect();
+    for group in groups {
+        for (event, value, time_enabled, time_running) in group.read()? {
+            counts[event].counts = value;
+            counts[event].time_enabled = time_enabled;
+            counts[event].time_running = time_running;
+        }
+    }
+    Ok(counts)
+}
+
+/// Counts between two reads of the counters
+fn difference(previous: &[Count], current: &[Count]) -> Vec<Count> {
+    previous.iter().zip(current).map(|(p, c)| Count {
+        counts: match c.counts {
+            -1 => -1,
+            counts => counts - p.counts.max(0)
+        },
+        time_enabled: c.time_enabled.saturating_sub(p.time_enabled),
+        time_running: c.time_running.saturating_sub(p.time_running),
+        ..c.clone()
+    }).collect()
+}
+
+/// Thread reading the counters every interval, until stopped
+struct Sampler {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<Vec<Interval>>>,
+}
+
+struct NativeSession {
+    events: Vec<String>,
+    groups: Arc<Vec<Group>>,
+    strict: bool,
+    sampler: Option<Sampler>,
+}
+
+impl NativeSession {
+    /// Open all events, events that cannot be opened are <not supported>
+    ///  Refused permission is an error, as it applies to all events.
+    fn open(events: &[String], strict: bool) -> Result<NativeSession> {
+        let mut pmus: HashMap<String, Pmu> = HashMap::new();
+        let mut groups: Vec<Group> = Vec::new();
+        // group of every XP (PMU, node ID without port and device bits)
+        let mut xp_groups: HashMap<(String, u64), usize> = HashMap::new();
+        for (i, event) in events.iter().enumerate() {
+            let (pmu_name, terms) = split_event(event)?;
+            if !pmus.contains_key(pmu_name) {
+                pmus.insert(String::from(pmu_name), Pmu::load(pmu_name)?);
+            }
+            let pmu = &pmus[pmu_name];
+            let mut attr = pmu.attr(terms)?;
+            let nodeid = pmu.formats.get("nodeid").map(|(_, ranges)| {
+                let (low, high) = ranges[0];
+                (attr.config >> low) & ((1 << (high - low + 1)) - 1)
+            }).unwrap_or(0);
+            let key = (String::from(pmu_name), nodeid >> 3);
+
+            let member = xp_groups.get(&key).copied().filter(|g| groups[*g].files.len() < GROUP_SIZE);
+            if let Some(g) = member {
+                match open(&mut attr, pmu.cpu, Some(&groups[g].files[0])) {
+                    Ok(file) => {
+                        groups[g].files.push(file);
+                        groups[g].events.push(i);
+                        continue
+                    }
+                    Err(errno) if is_permission(errno) => return Err(Error::PerfPermission),
+                    Err(errno) => debug!("Could not add {event} to its XP's group (errno {errno}), opening it alone")
+                }
+            }
+            match open(&mut attr, pmu.cpu, None) {
+                Ok(file) => {
+                    xp_groups.insert(key, groups.len());
+                    groups.push(Group { files: vec![file], events: vec![i] });
+                }
+                Err(errno) if is_permission(errno) => return Err(Error::PerfPermission),
+                Err(errno) => debug!("Could not open {event} (errno {errno}), not supported")
+            }
+        }
+        Ok(NativeSession { events: events.to_vec(), groups: Arc::new(groups), strict, sampler: None })
+    }
+
+    fn enable(&self) -> Result<()> {
+        for group in self.groups.iter() {
+            group.ioctl(sys::ioctls::RESET)?;
+            group.ioctl(sys::ioctls::ENABLE)?;
+        }
+        Ok(())
+    }
+
+    /// Read the counters every interval in the background, recording the counts of each interval
+    fn sample(&mut self, interval: Duration) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (events, groups, stopped) = (self.events.clone(), self.groups.clone(), stop.clone());
+        let thread = thread::spawn(move || {
+            let start = Instant::now();
+            let mut intervals = Vec::new();
+            let mut previous = read_counts(&events, &groups)?;
+            let mut end = interval;
+            loop {
+                while !stopped.load(Ordering::Relaxed) && start.elapsed() < end {
+                    sleep(end.saturating_sub(start.elapsed()).min(POLL_INTERVAL));
+                }
+                let current = read_counts(&events, &groups)?;
+                intervals.push(Interval { elapsed: start.elapsed(), counts: difference(&previous, &current) });
+                if stopped.load(Ordering::Relaxed) {
+                    return Ok(intervals)
+                }
+                previous = current;
+                end += interval;
+            }
+        });
+        self.sampler = Some(Sampler { stop, thread });
+    }
+}
+
+impl Session for NativeSession {
+    fn read(&mut self) -> Option<Counts> {
+        Some(Counts { counts: read_counts(&self.events, &self.groups).ok()?, ..Default::default() })
+    }
+
+    /// Stop the sampler (recording the last, partial interval), then disable and read the counters
+    fn stop(self: Box<Self>) -> Result<Counts> {
+        let intervals = match self.sampler {
+            Some(sampler) => {
+                sampler.stop.store(true, Ordering::Relaxed);
+                sampler.thread.join().expect("Sampler thread panicked")?
+            }
+            None => Vec::new()
+        };
+        for group in self.groups.iter() {
+            group.ioctl(sys::ioctls::DISABLE)?;
+        }
+        let counts = Counts { counts: read_counts(&self.events, &self.groups)?, intervals, report: String::new() };
+        counts.check_multiplexing(self.strict)?;
+        Ok(counts)
+    }
+}
+
+impl PerfBackend for PerfNative {
+    /// Open and enable the counters, run the command, and read the counters once it exited
+    fn stat(&self, events: &[String], mut command: Command, interval: Option<Duration>) -> Result<(Counts, Output)> {
+        let session = self.start(events, interval)?;
+        let output = command.stdin(Stdio::inherit()).output().map_err(Error::command(command.get_program()))?;
+        Ok((session.stop()?, output))
+    }
+
+    /// Open and enable the counters, with an interval they are read by a sampler thread every interval
+    fn start(&self, events: &[String], interval: Option<Duration>) -> Result<Box<dyn Session>> {
+        let mut session = NativeSession::open(events, self.strict)?;
+        session.enable()?;
+        if let Some(interval) = interval {
+            session.sample(interval);
+        }
+        Ok(Box::new(session))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Formats of the arm-cmn driver, plus one split over several ranges
+    fn cmn_pmu() -> Pmu {
+        let formats = [("type", "config:0-15"), ("eventid", "config:16-26"), ("occupid", "config:27-30"),
+                       ("bynodeid", "config:31"), ("nodeid", "config:32-47"), ("split", "config1:0-3,8-11")];
+        Pmu {
+            pmu_type: 42,
+            cpu: 0,
+            formats: formats.iter().map(|(n, f)| (String::from(*n), parse_format(f).unwrap())).collect(),
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(parse_format("config:0-15").unwrap(), (0, vec![(0, 15)]));
+        assert_eq!(parse_format("config:31").unwrap(), (0, vec![(31, 31)]));
+        assert_eq!(parse_format("config1:0-7,16-23").unwrap(), (1, vec![(0, 7), (16, 23)]));
+        assert_eq!(parse_format("config2:63").unwrap(), (2, vec![(63, 63)]));
+        assert!(matches!(parse_format("config3:0-7"), Err(Error::Parse { .. })));
+        assert!(matches!(parse_format("config:a-b"), Err(Error::Parse { .. })));
+        assert!(matches!(parse_format("config"), Err(Error::Parse { .. })));
+    }
+
+    #[test]
+    fn event_attr() {
+        let pmu = cmn_pmu();
+        let attr = pmu.attr("type=0x5,eventid=0x1,bynodeid=0x1,nodeid=0x68").unwrap();
+        assert_eq!(attr.type_, 42);
+        assert_eq!(attr.config, 0x5 | 0x1 << 16 | 1 << 31 | 0x68 << 32);
+
+        // flags without value are 1, decimal values are accepted as well
+        let attr = pmu.attr("type=5,eventid=0x1,bynodeid").unwrap();
+        assert_eq!(attr.config, 0x5 | 0x1 << 16 | 1 << 31);
+
+        // values are packed into the ranges from the lowest bits up, excess bits are dropped
+        let attr = pmu.attr("type=0x5,split=0x1ab").unwrap();
+        assert_eq!(attr.config, 0x5);
+        assert_eq!(unsafe { attr.__bindgen_anon_3.config1 }, 0xb | 0xa << 8);
+        let attr = pmu.attr("type=0x5,nodeid=0x1ffff").unwrap();
+        assert_eq!(attr.config, 0x5 | 0xffff << 32);
+
+        assert!(matches!(pmu.attr("type=0x5,unknown=0x1"), Err(Error::Config(_))));
+        assert!(matches!(pmu.attr("type=0xzz"), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn failed_open() {
+        // Debian's paranoid level 3 refuses every perf_event_open up front
+        if fs::read_to_string("/proc/sys/kernel/perf_event_paranoid").is_ok_and(|l| l.trim() == "3") {
+            return
+        }
+        // no PMU has this type, which the kernel rejects before checking the access to CPU-wide events
+        let mut attr = perf_event_attr { type_: u32::MAX, size: size_of::<perf_event_attr>() as u32, ..Default::default() };
+        attr.set_exclude_kernel(1);
+        attr.set_exclude_hv(1);
+        let errno = open(&mut attr, 0, None).unwrap_err();
+        assert_eq!(errno, libc::ENOENT);
+        assert!(!is_permission(errno));
+    }
+
+    #[test]
+    fn split_events() {
+        assert_eq!(split_event("arm_cmn_0/type=0x5,eventid=0x1/").unwrap(), ("arm_cmn_0", "type=0x5,eventid=0x1"));
+        assert!(matches!(split_event("arm_cmn_0"), Err(Error::Config(_))));
+    }
+}
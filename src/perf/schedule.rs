@@ -0,0 +1,318 @@
+/* Counter-aware scheduling: splits the events into passes fitting the XP and DTC counters */
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+
+use crate::args::EventSchedule;
+use crate::error::{Error, Result};
+use crate::perf::{copy_environment, Count, Counts, Interval, PerfBackend, Session};
+
+/// Local counters of a DTM, i.e. of every XP
+pub const XP_COUNTERS: usize = 4;
+/// Global counters of a DTC, taken by every event of the mesh in addition to its XP's counter
+///  Larger meshes may have several DTC domains with 8 counters each, assuming a single one always fits.
+pub const DTC_COUNTERS: usize = 8;
+/// How often to check whether the command exited while rotating
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Events of a pass, and the counters they take
+#[derive(Default)]
+struct Pass {
+    events: Vec<usize>,
+    /// Counters taken per CMN PMU
+    dtcs: HashMap<String, usize>,
+    /// Counters taken per XP (CMN PMU, node ID without port and device bits), None for those taken on every XP
+    xps: HashMap<(String, Option<u64>), usize>,
+}
+
+impl Pass {
+    /// XP counters an event would compete for, events without XP are counted by every XP of the CMN
+    fn xp_counters(&self, pmu: &str, xp: Option<u64>) -> usize {
+        let on_every_xp = self.xps.get(&(String::from(pmu), None)).copied().unwrap_or(0);
+        let on_xp = self.xps.iter()
+            .filter(|((p, x), _)| p == pmu && x.is_some() && (xp.is_none() || *x == xp))
+            .map(|(_, taken)| *taken)
+            .max()
+            .unwrap_or(0);
+        on_every_xp + on_xp
+    }
+}
+
+/// CMN PMU and XP of an event string (as built by `utils::get_event_string`), no XP if not counted by node ID
+fn locate(event: &str) -> (String, Option<u64>) {
+    let (pmu, terms) = event.split_once('/').unwrap_or((event, ""));
+    let xp = terms.trim_end_matches('/').split(',')
+        .find_map(|t| t.strip_prefix("nodeid="))
+        .and_then(|n| u64::from_str_radix(n.trim_start_matches("0x"), 16).ok())
+        .map(|nodeid| nodeid >> 3);
+    (String::from(pmu), xp)
+}
+
+/// Split the events into passes fitting the counters, as indices into `events`
+///  Every event takes a counter of its XP and one of the DTC, so a pass has at most XP_COUNTERS events per XP and
+///   DTC_COUNTERS events per CMN. Events without node ID take a counter of every XP.
+///  Each event is placed into the first pass with free counters, keeping their order.
+pub fn schedule(events: &[String]) -> Vec<Vec<usize>> {
+    let mut passes: Vec<Pass> = Vec::new();
+    for (i, event) in events.iter().enumerate() {
+        let (pmu, xp) = locate(event);
+        let fits = |p: &Pass| p.dtcs.get(&pmu).copied().unwrap_or(0) < DTC_COUNTERS
+            && p.xp_counters(&pmu, xp) < XP_COUNTERS;
+        let pass = match passes.iter().position(fits) {
+            Some(pass) => pass,
+            None => {
+                passes.push(Pass::default());
+                passes.len() - 1
+            }
+        };
+        passes[pass].events.push(i);
+        *passes[pass].dtcs.entry(pmu.clone()).or_default() += 1;
+        *passes[pass].xps.entry((pmu, xp)).or_default() += 1;
+    }
+    passes.into_iter().map(|p| p.events).collect()
+}
+
+/// Copy of the command (program, arguments, environment and working directory), to run it once per pass
+fn copy_command(command: &Command) -> Command {
+    let mut copy = Command::new(command.get_program());
+    copy.args(command.get_args());
+    copy_environment(&mut copy, command);
+    copy
+}
+
+/// Counts all <not supported>, until merged with those of their pass
+fn unsupported(events: &[String], passes: &[Vec<usize>]) -> Vec<Count> {
+    let mut counts: Vec<Count> = events.iter()
+        .map(|e| Count { event: e.clone(), counts: -1, time_enabled: 0, time_running: 0, pass: 0 })
+        .collect();
+    for (pass, indices) in passes.iter().enumerate() {
+        for i in indices {
+            counts[*i].pass = pass;
+        }
+    }
+    counts
+}
+
+/// Events of the given pass
+fn select(events: &[String], indices: &[usize]) -> Vec<String> {
+    indices.iter().map(|i| events[*i].clone()).collect()
+}
+
+/// Index into all events of every count of a pass, the backends return the counts in the order of the pass' events
+fn index(counts: &[Count], indices: &[usize]) -> Vec<(usize, Count)> {
+    indices.iter().copied().zip(counts.iter().cloned()).collect()
+}
+
+/// Backend counting the events in passes fitting the counters (see `EventSchedule`), using another backend per pass
+///  The counts are merged in the order of the events, each recording the pass it was counted in.
+pub struct Scheduler {
+    pub backend: Box<dyn PerfBackend>,
+    pub schedule: EventSchedule,
+    /// Time to count each pass before rotating to the next one (EventSchedule::Rotate)
+    pub rotate_interval: Duration,
+}
+
+impl Scheduler {
+    fn passes(&self, events: &[String]) -> Vec<Vec<usize>> {
+        match self.schedule {
+            EventSchedule::All => vec![(0..events.len()).collect()],
+            EventSchedule::Passes | EventSchedule::Rotate => schedule(events)
+        }
+    }
+
+    /// Run the command once per pass, the output of the first run is returned
+    ///  With an interval, the intervals of the passes are merged by their position, i.e. the time since their run started.
+    fn run_passes(&self, events: &[String], passes: &[Vec<usize>], command: Command, interval: Option<Duration>)
+                  -> Result<(Counts, Output)> {
+        let mut counts = Counts { counts: unsupported(events, passes), ..Default::default() };
+        let mut first_output = None;
+        for (pass, indices) in passes.iter().enumerate() {
+            debug!("Pass {}/{}: {} events", pass + 1, passes.len(), indices.len());
+            let (pass_counts, output) = self.backend.stat(&select(events, indices), copy_command(&command), interval)?;
+            for (i, count) in index(&pass_counts.counts, indices) {
+                counts.counts[i] = Count { pass, ..count };
+            }
+            for (k, pass_interval) in pass_counts.intervals.iter().enumerate() {
+                if counts.intervals.len() <= k {
+                    counts.intervals.push(Interval { elapsed: pass_interval.elapsed, counts: unsupported(events, passes) });
+                }
+                for (i, count) in index(&pass_interval.counts, indices) {
+                    counts.intervals[k].counts[i] = Count { pass, ..count };
+                }
+            }
+            counts.report.push_str(&pass_counts.report);
+            first_output.get_or_insert(output);
+        }
+        Ok((counts, first_output.unwrap()))
+    }
+
+    /// Run the command once, counting the passes in turn for rotate_interval each until it exits
+    ///  Like multiplexing by the kernel, the counts are scaled up by the time enabled (the whole run) / time running.
+    fn rotate(&self, events: &[String], passes: &[Vec<usize>], mut command: Command) -> Result<(Counts, Output)> {
+        let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+            .map_err(Error::command(command.get_program()))?;
+        // drain stdout and stderr, so the command does not block on full pipes
+        let drain = |mut pipe: Box<dyn Read + Send>| thread::spawn(move || {
+            let mut buffer = Vec::new();
+            pipe.read_to_end(&mut buffer).map(|_| buffer)
+        });
+        // both are piped above
+        let stdout = drain(Box::new(child.stdout.take().unwrap()));
+        let stderr = drain(Box::new(child.stderr.take().unwrap()));
+        let collect = |drain: thread::JoinHandle<io::Result<Vec<u8>>>| drain.join()
+            .unwrap_or_else(|_| Err(io::Error::other("reading the output panicked")))
+            .map_err(Error::command(command.get_program()));
+
+        let mut counts = Counts { counts: unsupported(events, passes), ..Default::default() };
+        let start = Instant::now();
+        let mut exited = false;
+        for (pass, indices) in passes.iter().enumerate().cycle() {
+            let session = self.backend.start(&select(events, indices), None)?;
+            let slice = Instant::now();
+            while !exited && slice.elapsed() < self.rotate_interval {
+                sleep(POLL_INTERVAL);
+                exited = child.try_wait().map_err(Error::command(command.get_program()))?.is_some();
+            }
+            let pass_counts = session.stop()?;
+            for (i, count) in index(&pass_counts.counts, indices) {
+                let merged = &mut counts.counts[i];
+                if count.counts >= 0 {
+                    merged.counts = merged.counts.max(0) + count.counts;
+                }
+                merged.time_running += count.time_running;
+            }
+            counts.report.push_str(&pass_counts.report);
+            if exited {
+                debug!("Command exited in pass {}/{}", pass + 1, passes.len());
+                break
+            }
+        }
+
+        let time_enabled = start.elapsed().as_nanos() as u64;
+        let mut never_counted = 0;
+        for count in counts.counts.iter_mut() {
+            count.time_enabled = time_enabled;
+            match count.time_running {
+                0 => never_counted += 1,
+                running => count.counts = match count.counts {
+                    -1 => -1,
+                    counts => (counts as u128 * time_enabled as u128 / running as u128) as i128
+                }
+            }
+        }
+        if never_counted > 0 {
+            warn!("{never_counted} events were never counted, the command exited before their pass, lower --rotate-interval");
+        }
+        let output = Output {
+            status: child.wait().map_err(Error::command(command.get_program()))?,
+            stdout: collect(stdout)?,
+            stderr: collect(stderr)?,
+        };
+        Ok((counts, output))
+    }
+}
+
+impl PerfBackend for Scheduler {
+    fn stat(&self, events: &[String], command: Command, interval: Option<Duration>) -> Result<(Counts, Output)> {
+        let passes = self.passes(events);
+        if passes.len() <= 1 {
+            return self.backend.stat(events, command, interval)
+        }
+        info!("Counting {} events in {} passes", events.len(), passes.len());
+        match self.schedule {
+            EventSchedule::Rotate => {
+                if interval.is_some() {
+                    warn!("Intervals are not recorded while rotating through the passes");
+                }
+                self.rotate(events, &passes, command)
+            }
+            _ => self.run_passes(events, &passes, command, interval)
+        }
+    }
+
+    /// Without a command to run per pass (or to rotate alongside), all events are counted at once
+    fn start(&self, events: &[String], interval: Option<Duration>) -> Result<Box<dyn Session>> {
+        let passes = self.passes(events).len();
+        if passes > 1 {
+            warn!("{} events need {passes} passes, but are counted at once without a command to schedule them around",
+                  events.len());
+        }
+        self.backend.start(events, interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(cmn_idx: u8, nodeid: u16, eventid: u16) -> String {
+        format!("arm_cmn_{cmn_idx}/type=0x4,eventid={eventid:#x},bynodeid=0x1,nodeid={nodeid:#x}/")
+    }
+
+    #[test]
+    fn locate_xp() {
+        assert_eq!(locate(&event(0, 0x68, 1)), (String::from("arm_cmn_0"), Some(0x68 >> 3)));
+        assert_eq!(locate(&event(1, 0x6c, 1)), (String::from("arm_cmn_1"), Some(0x68 >> 3)));
+        assert_eq!(locate("arm_cmn_0/type=0x5,eventid=0x1/"), (String::from("arm_cmn_0"), None));
+        assert_eq!(locate("arm_cmn_0"), (String::from("arm_cmn_0"), None));
+    }
+
+    #[test]
+    fn xp_counters() {
+        // ports and devices of one XP share its counters
+        let events: Vec<String> = (0..6).map(|i| event(0, 0x8 | (i % 2) << 2, i)).collect();
+        assert_eq!(schedule(&events), vec![vec![0, 1, 2, 3], vec![4, 5]]);
+    }
+
+    #[test]
+    fn dtc_counters() {
+        // 10 XPs with an event each still exceed the 8 counters of the DTC
+        let events: Vec<String> = (0..10).map(|i| event(0, i << 3, 1)).collect();
+        assert_eq!(schedule(&events), vec![(0..8).collect::<Vec<_>>(), vec![8, 9]]);
+
+        // every CMN has its own DTC
+        let events: Vec<String> = (0..16).map(|i| event((i % 2) as u8, i << 3, 1)).collect();
+        assert_eq!(schedule(&events), vec![(0..16).collect::<Vec<_>>()]);
+    }
+
+    #[test]
+    fn without_nodeid() {
+        // counted without node ID, they take a counter of every XP
+        let events: Vec<String> = (0..5).map(|i| format!("arm_cmn_0/type=0x5,eventid={i:#x}/")).collect();
+        assert_eq!(schedule(&events), vec![vec![0, 1, 2, 3], vec![4]]);
+
+        let events = vec![event(0, 0x8, 1), event(0, 0x8, 2), event(0, 0x10, 1),
+                          String::from("arm_cmn_0/type=0x5,eventid=0x1/"), String::from("arm_cmn_0/type=0x5,eventid=0x2/"),
+                          String::from("arm_cmn_0/type=0x5,eventid=0x3/"), event(0, 0x10, 2)];
+        assert_eq!(schedule(&events), vec![vec![0, 1, 2, 3, 4, 6], vec![5]]);
+    }
+
+    #[test]
+    fn keeps_order() {
+        let events = vec![event(0, 0x8, 1), event(0, 0x8, 2), event(0, 0x8, 3), event(0, 0x8, 4),
+                          event(0, 0x8, 5), event(0, 0x10, 1), event(0, 0x8, 6), event(0, 0x8, 1)];
+        let passes = schedule(&events);
+        assert_eq!(passes, vec![vec![0, 1, 2, 3, 5], vec![4, 6, 7]]);
+        // every event is scheduled exactly once
+        let mut scheduled: Vec<usize> = passes.concat();
+        scheduled.sort();
+        assert_eq!(scheduled, (0..events.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn index_duplicates() {
+        let event = event(0, 0x8, 1);
+        let counts = Counts {
+            counts: (1..=2).map(|c| Count { event: event.clone(), counts: c, time_enabled: 1, time_running: 1,
+                                            pass: 0 }).collect(),
+            ..Default::default()
+        };
+        let indexed: Vec<(usize, i128)> = index(&counts.counts, &[3, 7]).into_iter().map(|(i, c)| (i, c.counts)).collect();
+        assert_eq!(indexed, vec![(3, 1), (7, 2)]);
+    }
+}
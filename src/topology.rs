@@ -0,0 +1,118 @@
+/* CMN topology model, as derived from the determine-topology measurements */
+use std::fmt;
+use std::fmt::Formatter;
+use serde::{Deserialize, Serialize};
+
+use crate::args::NUMAConfig;
+
+/// Type of the device attached to an MXP port
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum NodeType {
+    /// Nothing detected besides the crosspoint itself
+    #[serde(rename = "XP")]
+    XpOnly,
+    #[serde(rename = "HN-F")]
+    HnF,
+    #[serde(rename = "HN-I")]
+    HnI,
+    #[serde(rename = "RN-D")]
+    RnD,
+    /// CCIX gateway (CXRA/CXHA, CCLA on CMN-700) to the other socket(s)
+    #[serde(rename = "CXG")]
+    Cxg,
+    /// Requesting node with a DSU (i.e. cores) attached
+    #[serde(rename = "RN-F")]
+    RnF,
+}
+
+impl fmt::Display for NodeType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            NodeType::XpOnly => "XP",
+            NodeType::HnF    => "HN-F",
+            NodeType::HnI    => "HN-I",
+            NodeType::RnD    => "RN-D",
+            NodeType::Cxg    => "CXG",
+            NodeType::RnF    => "RN-F",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Port {
+    pub port: u16,
+    pub node_type: NodeType,
+    /// Logical CPUs attached to this port (only for RN-F)
+    pub cpus: Vec<u16>,
+    /// Further node types the measurements suggest for this port, if they disagree with node_type
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicting_types: Vec<NodeType>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Xp {
+    pub x: u16,
+    pub y: u16,
+    pub ports: Vec<Port>,
+}
+
+/// Node ID encoding and dimensions of one CMN mesh
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct MeshInfo {
+    pub cmn_idx: u8,
+    pub nodeid_length: u8,
+    pub mesh_x: u16,
+    pub mesh_y: u16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Topology {
+    pub cmn_idx: u8,
+    pub nodeid_length: u8,
+    pub mesh_x: u16,
+    pub mesh_y: u16,
+    pub xps: Vec<Xp>,
+}
+
+impl Topology {
+    /// Location (x, y, port) of given logical CPU
+    pub fn cpu_location(&self, cpu: u16) -> Option<(u16, u16, u16)> {
+        self.xps.iter()
+            .flat_map(|xp| xp.ports.iter().map(move |p| (xp, p)))
+            .find(|(_, p)| p.cpus.contains(&cpu))
+            .map(|(xp, p)| (xp.x, xp.y, p.port))
+    }
+}
+
+/// NUMA node local to a CMN, with those of its CPUs attached to the CMN
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NumaNode {
+    pub node: u16,
+    pub cpus: Vec<u16>,
+}
+
+/// NUMA nodes of one CMN, as seen during determine-topology
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NumaLayout {
+    pub numa_config: NUMAConfig,
+    pub nodes: Vec<NumaNode>,
+}
+
+/// HN-Fs and memory controller (SN-F) ports serving the memory of one NUMA node
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NumaMemory {
+    pub node: u16,
+    pub cpus: Vec<u16>,
+    /// (x, y, port) of every HN-F which sent requests to a memory controller
+    pub hnfs: Vec<(u16, u16, u16)>,
+    /// (x, y, port) of every port without an observable node that received requests, i.e. the SN-Fs
+    pub memory_controllers: Vec<(u16, u16, u16)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemoryMap {
+    pub cmn_idx: u8,
+    pub numa_config: NUMAConfig,
+    pub nodes: Vec<NumaMemory>,
+}
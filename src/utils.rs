@@ -1,43 +1,219 @@
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use regex::Regex;
-use crate::event::{Event, NodeID};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde::de::DeserializeOwned;
+use crate::args::DetermineTopologyArgs;
+use crate::error::{Error, Result};
+use crate::event::NodeID;
+
+pub static PMU_SYSFS_PATH: &str = "/sys/bus/event_source/devices";
+static CPU_SYSFS_PATH: &str = "/sys/devices/system/cpu";
+
+/// Contents of a sysfs file
+pub fn read_sysfs(path: impl AsRef<Path>) -> Result<String> {
+    fs::read_to_string(path.as_ref()).map_err(Error::sysfs(path))
+}
+
+/// Indices of all CMN PMUs, i.e. every N of /sys/bus/event_source/devices/arm_cmn_N
+pub fn get_cmn_indices() -> Vec<u8> {
+    let Ok(devices) = fs::read_dir(PMU_SYSFS_PATH) else { return Vec::new() };
+    let mut indices: Vec<u8> = devices
+        .filter_map(|f| f.ok())
+        .filter_map(|f| f.file_name().to_str()?.strip_prefix("arm_cmn_")?.parse().ok())
+        .collect();
+    indices.sort();
+    indices
+}
+
+/// Name of the per-CMN folder in the output directory
+pub fn cmn_folder(cmn_idx: u8) -> String {
+    format!("cmn_{cmn_idx}")
+}
+
+/// Parse a CPU list as used by sysfs and taskset (e.g. `0-3,8,10-11`)
+pub fn parse_cpu_list(list: &str) -> Result<Vec<u16>> {
+    let parse = |cpu: &str| cpu.parse::<u16>().map_err(|_| Error::parse("CPU list", list.trim()));
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (parse(from)?, parse(to)?);
+                if from > to {
+                    return Err(Error::parse("CPU list", list.trim()))
+                }
+                cpus.extend(from..=to)
+            }
+            None => cpus.push(parse(range)?)
+        }
+    }
+    Ok(cpus)
+}
+
+pub fn get_online_cpus() -> Result<Vec<u16>> {
+    parse_cpu_list(read_sysfs(format!("{CPU_SYSFS_PATH}/online"))?.as_str())
+}
+
+/// Online CPUs attached to the given CMN
+///  The arm-cmn driver binds each PMU to a CPU local to its mesh (`cpumask`), so take all CPUs in the same package.
+///  On single-socket systems this is simply every online CPU.
+pub fn get_cmn_cpus(cmn_idx: u8) -> Result<Vec<u16>> {
+    let online = get_online_cpus()?;
+    let package = |cpu: u16| fs::read_to_string(
+        format!("{CPU_SYSFS_PATH}/cpu{cpu}/topology/physical_package_id")).ok()
+        .map(|p| String::from(p.trim()));
+
+    let pmu_cpu = fs::read_to_string(format!("{PMU_SYSFS_PATH}/arm_cmn_{cmn_idx}/cpumask")).ok()
+        .and_then(|mask| parse_cpu_list(mask.as_str()).ok()?.first().copied());
+    Ok(match pmu_cpu.and_then(package) {
+        Some(pmu_package) => online.into_iter().filter(|cpu| package(*cpu).as_ref() == Some(&pmu_package)).collect(),
+        None => online
+    })
+}
+
+/// NUMA nodes with memory and the given CPUs, i.e. (node, its CPUs among `cpus`) for every node in /sys/devices/system/node
+///  Nodes without any of the given CPUs (e.g. memory-only nodes or those of another socket) are left out.
+pub fn get_numa_nodes(cpus: &[u16]) -> Result<Vec<(u16, Vec<u16>)>> {
+    let node_path = "/sys/devices/system/node";
+    let Ok(with_memory) = fs::read_to_string(format!("{node_path}/has_memory")) else { return Ok(Vec::new()) };
+    let mut nodes = Vec::new();
+    for node in parse_cpu_list(with_memory.as_str())? {
+        let node_cpus = parse_cpu_list(read_sysfs(format!("{node_path}/node{node}/cpulist"))?.as_str())?;
+        let node_cpus: Vec<u16> = node_cpus.into_iter().filter(|c| cpus.contains(c)).collect();
+        if !node_cpus.is_empty() {
+            nodes.push((node, node_cpus));
+        }
+    }
+    Ok(nodes)
+}
+
+/// Group CPUs into clusters (i.e. CPUs behind the same DSU / MXP port)
+///  Uses topology/cluster_cpus_list, or the largest cache shared by a subset of the package's CPUs if not exposed.
+///  Offline CPUs are dropped from the clusters, clusters may differ in size.
+///  If cores_per_dsu is given, CPUs are simply grouped into chunks of that size instead.
+pub fn get_clusters(cpus: &[u16], cores_per_dsu: Option<u16>) -> Vec<Vec<u16>> {
+    match cores_per_dsu {
+        Some(cores_per_dsu) => cpus.chunks(cores_per_dsu as usize).map(|c| c.to_vec()).collect(),
+        None => read_clusters(Path::new(CPU_SYSFS_PATH), cpus)
+    }
+}
+
+/// Clusters of the given CPUs, read from the CPU topology below `cpu_sysfs_path` (/sys/devices/system/cpu)
+fn read_clusters(cpu_sysfs_path: &Path, cpus: &[u16]) -> Vec<Vec<u16>> {
+    let cpu_path = |cpu: u16, file: &str| format!("{}/cpu{cpu}/{file}", cpu_sysfs_path.display());
+    let read_list = |path: String| fs::read_to_string(path).ok().and_then(|l| parse_cpu_list(l.as_str()).ok());
+
+    let mut clusters: Vec<Vec<u16>> = Vec::new();
+    for cpu in cpus {
+        if clusters.iter().any(|c| c.contains(cpu)) {
+            continue
+        }
+        let package = read_list(cpu_path(*cpu, "topology/package_cpus_list")).unwrap_or(cpus.to_vec());
+
+        let mut candidates: Vec<Vec<u16>> = read_list(cpu_path(*cpu, "topology/cluster_cpus_list")).into_iter().collect();
+        if let Ok(indices) = fs::read_dir(cpu_path(*cpu, "cache")) {
+            candidates.extend(indices
+                .filter_map(|f| f.ok())
+                .filter(|f| f.file_name().to_string_lossy().starts_with("index"))
+                .filter_map(|f| read_list(format!("{}/shared_cpu_list", f.path().display()))));
+        }
+
+        let mut cluster = candidates.into_iter()
+            .filter(|c| c.len() < package.len())
+            .max_by_key(|c| c.len())
+            .unwrap_or(vec![*cpu]);
+        cluster.retain(|c| cpus.contains(c));
+        clusters.push(cluster);
+    }
+    clusters
+}
+
+/// Command running the benchmark: the external benchmark binary if given, otherwise the benchmark embedded in this binary
+pub fn benchmark_command(args: &DetermineTopologyArgs) -> Result<Command> {
+    Ok(match &args.benchmark_binary_path {
+        Some(path) => Command::new(path),
+        None => {
+            let mut cmd = Command::new(std::env::current_exe().map_err(Error::command("measurement benchmark"))?);
+            cmd.arg("benchmark");
+            cmd
+        }
+    })
+}
 
 pub fn get_event_string(cmn_idx: u8, x: u16, y: u16, port: u16, nodeid_length: u8, event: &str) -> String {
     format!("arm_cmn_{cmn_idx}/{event},bynodeid=0x1,nodeid={:#0x}/", NodeID {x,y,port,nodeid_length}.to_nodeid())
 }
 
-pub fn get_event_type_id(event_name: &str) -> String {
-    let fcontent = fs::read_to_string(format!("/sys/bus/event_source/devices/arm_cmn_0/events/{event_name}")).unwrap();
-    return String::from(fcontent.trim());
+pub fn get_event_type_id(cmn_idx: u8, event_name: &str) -> Result<String> {
+    let fcontent = read_sysfs(format!("{PMU_SYSFS_PATH}/arm_cmn_{cmn_idx}/events/{event_name}"))?;
+    Ok(String::from(fcontent.trim()))
+}
+
+/// First event (by name) of the CMN PMU starting with `prefix`, None if there is none
+///  Newer kernels only expose the events of node types present in the mesh.
+pub fn find_event(cmn_idx: u8, prefix: &str) -> Option<String> {
+    fs::read_dir(format!("{PMU_SYSFS_PATH}/arm_cmn_{cmn_idx}/events")).ok()?
+        .filter_map(|f| f.ok())
+        .filter_map(|f| f.file_name().into_string().ok())
+        .filter(|name| name.starts_with(prefix))
+        .min()
+}
+
+/// Split a sysfs event description (e.g. `type=0x5,eventid=0x1`) into its type and event ID
+pub fn parse_event_type_id(type_id: &str) -> Option<(u8, u16)> {
+    let mut event_type = None;
+    let mut event_id = None;
+    for field in type_id.trim().split(',') {
+        match field.split_once('=') {
+            Some(("type", v)) => event_type = u8::from_str_radix(v.trim_start_matches("0x"), 16).ok(),
+            Some(("eventid", v)) => event_id = u16::from_str_radix(v.trim_start_matches("0x"), 16).ok(),
+            _ => {}
+        }
+    }
+    Some((event_type?, event_id?))
+}
+
+/// Read back an `events.csv` (see `events_map_to_vec`) into event name -> (type, event ID)
+pub fn read_events_map(path: &Path) -> Result<HashMap<String, (u8, u16)>> {
+    Ok(fs::read_to_string(path).map_err(Error::io(path))?
+        .lines()
+        .filter_map(|l| l.split_once(';'))
+        .filter_map(|(name, type_id)| Some((String::from(name.trim_matches('"')),
+                                            parse_event_type_id(type_id.trim_matches('"'))?)))
+        .collect())
 }
 
-pub fn perf_to_event_vec(s: &str, nodeid_length: u8) -> Vec<Event> {
-    let pattern = Regex::new(
-        r"^(\d+|<not supported>|<not counted>);;arm_cmn_(\d)\/type=(.*?),eventid=(.*?),bynodeid=0x1,nodeid=(.*?)\/.*?$")
-        .unwrap();
+/// Event name -> type and ID lookup, for the events that must be present in events.csv
+pub fn get_event(events_map: &HashMap<String, (u8, u16)>, name: &str) -> Result<(u8, u16)> {
+    events_map.get(name).copied().ok_or_else(|| Error::Config(format!("Event {name} not found in events.csv")))
+}
 
-    s.split("\n")
-        .filter_map(|p| pattern.captures(p))
-        .map(|c| Event::from_captures(c, nodeid_length))
-        .collect::<Vec<Event>>()
+/// Parsed JSON file of a previous run, None if it does not exist
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(content.as_str())
+            .map(Some)
+            .map_err(|e| Error::parse(path.display().to_string(), e.to_string())),
+        Err(_) => Ok(None)
+    }
 }
 
-pub fn events_map_to_vec() -> Vec<String> {
-    let basepath = PathBuf::from("/sys/bus/event_source/devices/arm_cmn_0/events");
+pub fn events_map_to_vec(cmn_idx: u8) -> Result<Vec<String>> {
+    let basepath = PathBuf::from(format!("{PMU_SYSFS_PATH}/arm_cmn_{cmn_idx}/events"));
 
     let mut vec = Vec::new();
-    for file in fs::read_dir(basepath.as_path()).unwrap() {
-        let _file = file.unwrap();
+    for file in fs::read_dir(basepath.as_path()).map_err(Error::sysfs(&basepath))? {
+        let _file = file.map_err(Error::sysfs(&basepath))?;
         vec.push(format!("{:?};{:?}",
                          _file.file_name(),
-                         fs::read_to_string(_file.path().as_path()).unwrap().trim()));
+                         read_sysfs(_file.path())?.trim()));
     }
 
-    vec
+    Ok(vec)
 }
 
-pub fn events_to_perf_events(events: Vec<String>, node_x: u16, node_y: u16, nodeid_length: u8) -> Vec<String> {
+pub fn events_to_perf_events(cmn_idx: u8, events: Vec<String>, node_x: u16, node_y: u16, nodeid_length: u8)
+                             -> Result<Vec<String>> {
     let mut perf_events = Vec::new();
     for event in events {
         let mut ports = Vec::new();
@@ -56,12 +232,37 @@ pub fn events_to_perf_events(events: Vec<String>, node_x: u16, node_y: u16, node
         }
 
         for port in ports {
-            perf_events.push(String::from("-e"));
-            perf_events.push(get_event_string(0, node_x, node_y, port, nodeid_length,
-                                              &get_event_type_id(parsed_event.as_str())));
+            perf_events.push(get_event_string(cmn_idx, node_x, node_y, port, nodeid_length,
+                                              &get_event_type_id(cmn_idx, parsed_event.as_str()).map_err(|_| Error::Config(
+                                                  format!("Unknown CMN event `{parsed_event}`, see events.csv")))?));
         }
 
     }
-    perf_events
+    Ok(perf_events)
+
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-}
\ No newline at end of file
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n").unwrap(), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list("5").unwrap(), vec![5]);
+        assert!(parse_cpu_list("").unwrap().is_empty());
+        assert!(parse_cpu_list("\n").unwrap().is_empty());
+        for malformed in ["3-1", "1-", "-1", "1-2-3", "a", "0,x-3"] {
+            assert!(matches!(parse_cpu_list(malformed), Err(Error::Parse { .. })), "{malformed}");
+        }
+    }
+
+    #[test]
+    fn clusters() {
+        // CPUs 0-3 expose cluster_cpus_list, 4-6 only their caches; CPU 3 is offline
+        let cpu_sysfs_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/sysfs_cpu");
+        let online = parse_cpu_list(fs::read_to_string(cpu_sysfs_path.join("online")).unwrap().as_str()).unwrap();
+        assert_eq!(read_clusters(cpu_sysfs_path.as_path(), &online), vec![vec![0, 1], vec![2], vec![4, 5, 6]]);
+
+        assert_eq!(get_clusters(&online, Some(4)), vec![vec![0, 1, 2, 4], vec![5, 6]]);
+    }
+}
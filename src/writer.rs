@@ -5,9 +5,11 @@ use std::path::{PathBuf};
 use std::process::Command;
 
 use chrono::{DateTime, Local};
+use serde::Serialize;
 
 use crate::args::{Cli, Commands};
-use crate::event::Event;
+use crate::error::{Error, Result};
+use crate::event::{Event, TimedEvent, EVENT_COLUMNS};
 
 pub struct Writer {
     pub basepath: Option<String>,
@@ -22,20 +24,29 @@ pub struct Writer {
 }
 
 impl Writer {
-    pub fn new(basepath: Option<String>, args: &Cli) -> Writer {
+    pub fn new(basepath: Option<String>, args: &Cli) -> Result<Writer> {
         let datetime = Local::now();
 
         let prefix = match args.command {
             Commands::DetermineTopology(_)   => "determine_topology",
+            Commands::AnalyzeTopology(_)     => "analyze_topology",
+            Commands::RouteCheck(_)          => "route_check",
+            Commands::DiffTopology(_)        => "diff_topology",
             Commands::Launch(_)         => "launch",
             Commands::LaunchMulti(_)    => "launch_multi",
+            Commands::Benchmark(_)      => "benchmark",
         };
         let mut w = Writer {
             basepath,
             args: format!("{:?}", args),
-            hostname: String::from_utf8(Command::new("hostname").output().unwrap().stdout).unwrap().trim().parse().unwrap(),
-            kernel: String::from_utf8(Command::new("uname").arg("-a").output().unwrap().stdout).unwrap().trim().parse().unwrap(),
-            perf_version: String::from_utf8(Command::new("perf").arg("--version").output().unwrap().stdout).unwrap().trim().parse().unwrap(),
+            hostname: command_output("hostname", &[])
+                .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok().map(|h| String::from(h.trim())))
+                .unwrap_or_else(|| String::from("unknown")),
+            kernel: command_output("uname", &["-a"])
+                .or_else(|| fs::read_to_string("/proc/version").ok().map(|v| String::from(v.trim())))
+                .unwrap_or_else(|| String::from("unknown")),
+            // the native perf backend does not need the perf binary
+            perf_version: command_output("perf", &["--version"]).unwrap_or_else(|| String::from("not installed")),
             datetime,
             basedir: "".to_string(),
             prefix: prefix.to_string(),
@@ -43,8 +54,8 @@ impl Writer {
         };
         w.basedir = w.gen_basedir();
 
-        w.create_outdir();
-        w
+        w.create_outdir()?;
+        Ok(w)
     }
 
     fn gen_basedir(&self) -> String {
@@ -57,7 +68,7 @@ impl Writer {
         out_path
     }
 
-    fn create_outdir(&mut self) {
+    fn create_outdir(&mut self) -> Result<()> {
         if self.basepath.is_some() {
             let mut out_path = self.get_outpath();
             if out_path.is_dir() { // if already exists (two runs within one minute), then attach "-$i" to dirname until not exists
@@ -70,55 +81,102 @@ impl Writer {
                     i += 1;
                 }
             }
-            fs::create_dir_all(out_path.as_path()).expect("Could not create measurement folder!");
+            fs::create_dir_all(out_path.as_path()).map_err(Error::io(&out_path))?;
         }
+        Ok(())
     }
 
-    pub fn write_events(&self, events: &Vec<Event>, event_type: &str, folder: Option<&str>) {
-        if self.basepath.is_none() {
-            log::debug!("Writer nas no basepath, will not write events.");
-            return
-        }
-
+    /// Output path, extended by (and creating) the given folder
+    fn get_folder_path(&self, folder: Option<&str>) -> Result<PathBuf> {
         let mut out_path = self.get_outpath();
         if let Some(_folder) = folder {
             out_path.push(_folder);
 
             if !out_path.is_dir() {
-                fs::create_dir(out_path.clone()).expect("Could not create directory");
+                fs::create_dir_all(out_path.clone()).map_err(Error::io(&out_path))?;
             }
         }
+        Ok(out_path)
+    }
 
+    pub fn write_events(&self, events: &[Event], event_type: &str, folder: Option<&str>) -> Result<()> {
+        if self.basepath.is_none() {
+            log::debug!("Writer nas no basepath, will not write events.");
+            return Ok(())
+        }
+
+        let mut out_path = self.get_folder_path(folder)?;
         out_path.push(format!("{event_type}.csv"));
         let mut csv_writer = csv::WriterBuilder::new()
             .delimiter(b';')
             .from_writer(vec![]);
-        events.iter().for_each(|e| csv_writer.serialize(e).expect("Could not serialize Event!"));
-        fs::write(out_path.as_path(), String::from_utf8(csv_writer.into_inner().unwrap()).unwrap())
-            .expect("Could not write events to file!");
+        for event in events {
+            csv_writer.serialize(event).expect("Could not serialize Event!");
+        }
+        fs::write(out_path.as_path(), csv_writer.into_inner().expect("Could not serialize Event!"))
+            .map_err(Error::io(&out_path))
+    }
+
+    /// Events of every interval as one CSV, each row prefixed by the time (s) since counting started
+    pub fn write_time_series(&self, intervals: &[(f64, Vec<Event>)], name: &str, folder: Option<&str>) -> Result<()> {
+        if self.basepath.is_none() {
+            log::debug!("Writer nas no basepath, will not write time series.");
+            return Ok(())
+        }
+
+        let mut out_path = self.get_folder_path(folder)?;
+        out_path.push(format!("{name}.csv"));
+        let mut csv_writer = csv::WriterBuilder::new()
+            .delimiter(b';')
+            .from_writer(vec![]);
+        let mut empty = true;
+        for (time, events) in intervals {
+            for event in events {
+                csv_writer.serialize(TimedEvent { time_s: *time, event }).expect("Could not serialize Event!");
+                empty = false;
+            }
+        }
+        // the header is written along with the first row, so without any it has to be written explicitly
+        if empty {
+            csv_writer.write_record(["time_s"].iter().chain(EVENT_COLUMNS.iter()))
+                .expect("Could not serialize Event!");
+        }
+        fs::write(out_path.as_path(), csv_writer.into_inner().expect("Could not serialize Event!"))
+            .map_err(Error::io(&out_path))
     }
 
-    pub fn write_lines(&self, lines: Vec<String>, fname: &str) {
+    pub fn write_lines(&self, lines: Vec<String>, fname: &str, folder: Option<&str>) -> Result<()> {
         if self.basepath.is_none() {
             log::debug!("Writer nas no basepath, will not write lines.");
-            return
+            return Ok(())
         }
 
-        let mut out_path = self.get_outpath();
+        let mut out_path = self.get_folder_path(folder)?;
         out_path.push(fname);
-        fs::write(out_path.as_path(), lines.join("\n"))
-            .expect("Could not write lines to file!");
+        fs::write(out_path.as_path(), lines.join("\n")).map_err(Error::io(&out_path))
     }
 
-    pub fn write_meta(&self) {
+    pub fn write_json<T: Serialize>(&self, value: &T, fname: &str, folder: Option<&str>) -> Result<()> {
+        if self.basepath.is_none() {
+            log::debug!("Writer nas no basepath, will not write json.");
+            return Ok(())
+        }
+
+        let mut out_path = self.get_folder_path(folder)?;
+        out_path.push(fname);
+        fs::write(out_path.as_path(), serde_json::to_string_pretty(value).expect("Could not serialize to json!"))
+            .map_err(Error::io(&out_path))
+    }
+
+    pub fn write_meta(&self) -> Result<()> {
         let now = Local::now();
         if self.basepath.is_none() {
             log::debug!("Writer nas no basepath, will not write meta.");
-            return
+            return Ok(())
         }
 
         let mut out_path = self.get_outpath();
-        out_path.push(format!("meta.md"));
+        out_path.push("meta.md");
         let content = format!("\
 Measurement Start: `{}` (epoch: `{}`)
 Measurement End  : `{}` (epoch: `{}`)
@@ -128,15 +186,21 @@ Perf Version: `{}`
 All Args: `{}`
 Additional Args: `{}`
 ",
-                              self.datetime.format("%Y-%m-%dT%H%M%S%Z").to_string(),
+                              self.datetime.format("%Y-%m-%dT%H%M%S%Z"),
                               self.datetime.format("%s"),
-                              now.format("%Y-%m-%dT%H%M%S%Z").to_string(),
+                              now.format("%Y-%m-%dT%H%M%S%Z"),
                               now.format("%s"),
                               self.hostname,
                               self.kernel,
                               self.perf_version,
                               self.args,
                               self.additional_args);
-        fs::write(out_path.as_path(), content).expect("Could not write meta file!");
+        fs::write(out_path.as_path(), content).map_err(Error::io(&out_path))
     }
+}
+
+/// Trimmed stdout of the program, None if it could not be run
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
\ No newline at end of file